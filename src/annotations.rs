@@ -0,0 +1,122 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::OverlayItem;
+use crate::overlay::OverlayKind;
+
+/// Contents of an annotations sidecar file.
+#[derive(Serialize, Deserialize)]
+struct Sidecar {
+    /// Absolute path of the annotated file, so a sidecar copied or moved
+    /// next to another file isn't applied to it.
+    path: PathBuf,
+    annotations: Vec<Annotation>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Annotation {
+    /// 1-based line number the annotation is shown after.
+    after_line: usize,
+    /// Hash of the anchor line, used to find it again after edits. Absent for
+    /// annotations past the end of the file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    anchor_hash: Option<String>,
+    content: Vec<String>,
+    #[serde(default)]
+    kind: OverlayKind,
+    #[serde(default)]
+    collapsed: bool,
+}
+
+/// Sidecar path for `path`, e.g. `src/.main.rs.annotations.json`.
+pub fn sidecar_path(path: &Path) -> PathBuf {
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    path.with_file_name(format!(".{name}.annotations.json"))
+}
+
+/// `path` made absolute where possible, so the same file viewed through
+/// different relative paths compares equal.
+fn resolve(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// 64-bit FNV-1a, stable across builds so saved hashes stay valid.
+fn line_hash(line: &str) -> String {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in line.bytes() {
+        hash ^= u64::from(b);
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    format!("{hash:016x}")
+}
+
+/// Writes `overlays` to the sidecar file for `path`.
+pub fn save(path: &Path, lines: &[String], overlays: &[OverlayItem]) -> io::Result<PathBuf> {
+    let sidecar = Sidecar {
+        path: resolve(path),
+        annotations: overlays
+            .iter()
+            .map(|o| Annotation {
+                after_line: o.after_line + 1,
+                anchor_hash: lines.get(o.after_line).map(|l| line_hash(l)),
+                content: o.content.clone(),
+                kind: o.kind,
+                collapsed: o.collapsed,
+            })
+            .collect(),
+    };
+    let json = serde_json::to_string_pretty(&sidecar).map_err(io::Error::other)?;
+    let target = sidecar_path(path);
+    fs::write(&target, json)?;
+    Ok(target)
+}
+
+/// Reads the sidecar file for `path` and anchors each annotation to the line
+/// whose hash matches, preferring the saved line number and otherwise the
+/// nearest matching line. Returns the overlays and the number dropped because
+/// their anchor line no longer exists. A sidecar saved for a different file
+/// is an error.
+pub fn load(path: &Path, lines: &[String]) -> io::Result<(Vec<OverlayItem>, usize)> {
+    let json = fs::read_to_string(sidecar_path(path))?;
+    let sidecar: Sidecar = serde_json::from_str(&json).map_err(io::Error::other)?;
+    if resolve(&sidecar.path) != resolve(path) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("saved for {}", sidecar.path.display()),
+        ));
+    }
+    let hashes: Vec<String> = lines.iter().map(|l| line_hash(l)).collect();
+    let mut items = Vec::new();
+    let mut dropped = 0;
+    for a in sidecar.annotations {
+        let saved = a.after_line.saturating_sub(1);
+        let after_line = match &a.anchor_hash {
+            None => Some(saved.max(lines.len())),
+            Some(hash) => hashes
+                .iter()
+                .enumerate()
+                .filter(|(_, h)| *h == hash)
+                .min_by_key(|(i, _)| i.abs_diff(saved))
+                .map(|(i, _)| i),
+        };
+        match after_line {
+            Some(after_line) => items.push(OverlayItem {
+                id: 0,
+                after_line,
+                content: a.content,
+                kind: a.kind,
+                collapsed: a.collapsed,
+            }),
+            None => dropped += 1,
+        }
+    }
+    items.sort_by_key(|o| o.after_line);
+    Ok((items, dropped))
+}
@@ -1,9 +1,26 @@
 use std::str::FromStr;
 
+/// Placeholder that expands to the arguments typed after the command name.
+/// Standing alone and unquoted it becomes one argument per typed word.
+pub const ARGS_PLACEHOLDER: &str = "args";
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct CommandSpec {
     pub name: String,
     pub template: String,
+    /// Capture stdout and show it inline instead of discarding it.
+    /// Enabled by suffixing the name with `!`, e.g. `lint!:cargo clippy`.
+    pub capture: bool,
+    /// Run the template with `sh -c` so pipes and redirections work.
+    /// Enabled by suffixing the name with `$`, e.g. `todo$:grep -n TODO {file} | head`.
+    pub shell: bool,
+    /// Pipe the selection, or the whole file without one, to stdin.
+    /// Enabled by suffixing the name with `|`, e.g. `jq|!:jq .`.
+    pub stdin: bool,
+    /// Hand the terminal to the command and wait for it, for editors, pagers
+    /// and other interactive programs. Enabled by suffixing the name with
+    /// `@`, e.g. `edit@:vi +{line} {file}`.
+    pub foreground: bool,
 }
 
 impl FromStr for CommandSpec {
@@ -14,14 +31,243 @@ impl FromStr for CommandSpec {
         if parts.len() != 2 {
             return Err("expected <name>: <template>".into());
         }
-        let name = parts[0].trim();
+        let mut name = parts[0].trim();
         let template = parts[1].trim();
+        let (mut capture, mut shell, mut stdin, mut foreground) = (false, false, false, false);
+        loop {
+            if let Some(n) = name.strip_suffix('!') {
+                capture = true;
+                name = n;
+            } else if let Some(n) = name.strip_suffix('$') {
+                shell = true;
+                name = n;
+            } else if let Some(n) = name.strip_suffix('|') {
+                stdin = true;
+                name = n;
+            } else if let Some(n) = name.strip_suffix('@') {
+                foreground = true;
+                name = n;
+            } else {
+                break;
+            }
+        }
+        let name = name.trim_end();
         if name.is_empty() || template.is_empty() {
             return Err("name or template empty".into());
         }
+        if capture && foreground {
+            return Err("`!` and `@` can't be combined".into());
+        }
         Ok(CommandSpec {
             name: name.to_string(),
             template: template.to_string(),
+            capture,
+            shell,
+            stdin,
+            foreground,
         })
     }
 }
+
+impl CommandSpec {
+    /// Expands the template into the program and its arguments. `vars` gives
+    /// the value of each `{placeholder}`; unknown ones are left as written.
+    /// Shell commands become `sh -c <script>` with every value quoted.
+    pub fn argv(&self, vars: &dyn Fn(&str) -> Option<String>) -> Result<Vec<String>, String> {
+        let argv = if self.shell {
+            vec![
+                "sh".into(),
+                "-c".into(),
+                expand_script(&self.template, vars)?,
+            ]
+        } else {
+            expand_words(&self.template, vars)?
+        };
+        if argv.is_empty() {
+            return Err(format!("{}: nothing to run", self.name));
+        }
+        Ok(argv)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Quote {
+    None,
+    Single,
+    Double,
+}
+
+/// Characters a backslash escapes inside double quotes.
+const DOUBLE_QUOTE_ESCAPES: &[char] = &['"', '\\', '$', '`'];
+
+/// The placeholder name and the byte offset past its `}` when `{` at `at`
+/// starts one.
+fn placeholder_at(template: &str, at: usize) -> Option<(&str, usize)> {
+    let rest = template[at..].strip_prefix('{')?;
+    let end = rest.find('}')?;
+    let name = &rest[..end];
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return None;
+    }
+    Some((name, at + end + 2))
+}
+
+/// Whether the placeholder spanning `start..end` is a whole unquoted word.
+fn stands_alone(template: &str, start: usize, end: usize) -> bool {
+    template[..start]
+        .chars()
+        .next_back()
+        .is_none_or(char::is_whitespace)
+        && template[end..]
+            .chars()
+            .next()
+            .is_none_or(char::is_whitespace)
+}
+
+/// Splits `template` into words the way a POSIX shell would, honouring
+/// single quotes, double quotes and backslashes, but without any other
+/// shell syntax. Placeholder values are never split or reinterpreted, with
+/// the exception of a standalone unquoted `{args}`.
+pub fn expand_words(
+    template: &str,
+    vars: &dyn Fn(&str) -> Option<String>,
+) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut quote = Quote::None;
+    let mut chars = template.char_indices().peekable();
+    while let Some((at, c)) = chars.next() {
+        match (quote, c) {
+            (Quote::None, c) if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            (Quote::None, '\'') => {
+                quote = Quote::Single;
+                in_word = true;
+            }
+            (Quote::None, '"') => {
+                quote = Quote::Double;
+                in_word = true;
+            }
+            (Quote::Single, '\'') | (Quote::Double, '"') => quote = Quote::None,
+            (Quote::None, '\\') => {
+                if let Some((_, next)) = chars.next() {
+                    word.push(next);
+                }
+                in_word = true;
+            }
+            (Quote::Double, '\\') => match chars.peek() {
+                Some(&(_, next)) if DOUBLE_QUOTE_ESCAPES.contains(&next) => {
+                    word.push(next);
+                    chars.next();
+                }
+                _ => word.push('\\'),
+            },
+            (_, '{') if let Some((name, end)) = placeholder_at(template, at) => {
+                let Some(value) = vars(name) else {
+                    word.push(c);
+                    in_word = true;
+                    continue;
+                };
+                while chars.peek().is_some_and(|&(i, _)| i < end) {
+                    chars.next();
+                }
+                if name == ARGS_PLACEHOLDER
+                    && quote == Quote::None
+                    && stands_alone(template, at, end)
+                {
+                    words.extend(expand_words(&value, &|_| None)?);
+                } else {
+                    word.push_str(&value);
+                    in_word = true;
+                }
+            }
+            (_, c) => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if quote != Quote::None {
+        return Err("Unterminated quote in command".to_string());
+    }
+    if in_word {
+        words.push(word);
+    }
+    Ok(words)
+}
+
+/// Substitutes placeholders into a `sh -c` script, quoting each value for
+/// the context it appears in so it stays a single literal word. A standalone
+/// unquoted `{args}` becomes one quoted word per typed argument.
+pub fn expand_script(
+    template: &str,
+    vars: &dyn Fn(&str) -> Option<String>,
+) -> Result<String, String> {
+    let mut script = String::with_capacity(template.len());
+    let mut quote = Quote::None;
+    let mut chars = template.char_indices().peekable();
+    while let Some((at, c)) = chars.next() {
+        match (quote, c) {
+            (Quote::None, '\'') => quote = Quote::Single,
+            (Quote::None, '"') => quote = Quote::Double,
+            (Quote::Single, '\'') | (Quote::Double, '"') => quote = Quote::None,
+            (Quote::None | Quote::Double, '\\') => {
+                script.push(c);
+                if let Some((_, next)) = chars.next() {
+                    script.push(next);
+                }
+                continue;
+            }
+            (_, '{') if let Some((name, end)) = placeholder_at(template, at) => {
+                if let Some(value) = vars(name) {
+                    while chars.peek().is_some_and(|&(i, _)| i < end) {
+                        chars.next();
+                    }
+                    let quoted = match quote {
+                        Quote::None
+                            if name == ARGS_PLACEHOLDER && stands_alone(template, at, end) =>
+                        {
+                            expand_words(&value, &|_| None)?
+                                .iter()
+                                .map(|arg| shell_quote(arg))
+                                .collect::<Vec<_>>()
+                                .join(" ")
+                        }
+                        Quote::None => shell_quote(&value),
+                        Quote::Single => value.replace('\'', "'\\''"),
+                        Quote::Double => value
+                            .chars()
+                            .flat_map(|c| {
+                                let escape = DOUBLE_QUOTE_ESCAPES.contains(&c).then_some('\\');
+                                escape.into_iter().chain(std::iter::once(c))
+                            })
+                            .collect(),
+                    };
+                    script.push_str(&quoted);
+                    continue;
+                }
+            }
+            _ => {}
+        }
+        script.push(c);
+    }
+    if quote != Quote::None {
+        return Err("Unterminated quote in command".to_string());
+    }
+    Ok(script)
+}
+
+/// Quotes `s` as a single `sh` word, leaving plain words untouched.
+pub fn shell_quote(s: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "_-./=:,+@%".contains(c);
+    if !s.is_empty() && s.chars().all(plain) {
+        s.to_string()
+    } else {
+        format!("'{}'", s.replace('\'', "'\\''"))
+    }
+}
@@ -1,7 +1,7 @@
 use ratatui::crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
 
 use crate::command_spec::CommandSpec;
-use crate::{App, Mode};
+use crate::{App, ForegroundCommand, Mode};
 
 #[derive(Clone, Copy)]
 pub enum EditorCommand {
@@ -12,9 +12,18 @@ pub enum EditorCommand {
     EnterHelp,
     GotoLastLine,
     EnterSearch,
+    EnterSearchBackward,
     NextHit,
     PrevHit,
+    SearchWordForward,
+    SearchWordBackward,
+    NextOverlay,
+    PrevOverlay,
+    ToggleOverlay,
+    ExpandAllOverlays,
+    CollapseAllOverlays,
     EnterCommand,
+    EnterFilter,
     MoveLeft,
     MoveDown,
     MoveUp,
@@ -39,11 +48,30 @@ pub enum EditorCommand {
     SearchSubmit,
     SearchBackspace,
     SearchChar(char),
+    SearchHistoryPrev,
+    SearchHistoryNext,
+    StartNote,
+    DeleteOverlay,
+    SaveNote,
+    CancelNote,
+    NoteNewline,
+    NoteBackspace,
+    NoteChar(char),
+    CancelJob,
+    OpenFinder,
+    CloseFinder,
+    FinderSubmit,
+    FinderNext,
+    FinderPrev,
+    FinderBackspace,
+    FinderChar(char),
 }
 
 pub struct Context {
     pub height: u16,
     pub pending_g: bool,
+    /// First key of a two-key sequence such as `]o`.
+    pub pending_prefix: Option<char>,
 }
 
 impl EditorCommand {
@@ -53,11 +81,13 @@ impl EditorCommand {
             EditorCommand::EnterVisual => {
                 app.mode = Mode::Visual;
                 app.selection_start = Some((app.cursor_y, app.cursor_x));
+                app.selection_linewise = false;
                 ctx.pending_g = false;
             }
             EditorCommand::EnterVisualLine => {
                 app.mode = Mode::VisualLine;
                 app.selection_start = Some((app.cursor_y, app.cursor_x));
+                app.selection_linewise = true;
                 ctx.pending_g = false;
             }
             EditorCommand::GotoFirstOrPending => {
@@ -79,15 +109,30 @@ impl EditorCommand {
                 ctx.pending_g = false;
             }
             EditorCommand::EnterSearch => {
-                app.mode = Mode::Search(String::new());
+                app.begin_search(false);
+                ctx.pending_g = false;
+            }
+            EditorCommand::EnterSearchBackward => {
+                app.begin_search(true);
                 ctx.pending_g = false;
             }
             EditorCommand::NextHit => app.next_hit(ctx.height),
             EditorCommand::PrevHit => app.prev_hit(ctx.height),
+            EditorCommand::SearchWordForward => app.search_word(false, ctx.height),
+            EditorCommand::SearchWordBackward => app.search_word(true, ctx.height),
+            EditorCommand::NextOverlay => app.next_overlay(ctx.height),
+            EditorCommand::PrevOverlay => app.prev_overlay(ctx.height),
+            EditorCommand::ToggleOverlay => app.toggle_overlay(ctx.height),
+            EditorCommand::ExpandAllOverlays => app.set_all_overlays_collapsed(false, ctx.height),
+            EditorCommand::CollapseAllOverlays => app.set_all_overlays_collapsed(true, ctx.height),
             EditorCommand::EnterCommand => {
                 app.mode = Mode::Command(String::new());
                 ctx.pending_g = false;
             }
+            EditorCommand::EnterFilter => {
+                app.mode = Mode::Command("filter ".to_string());
+                ctx.pending_g = false;
+            }
             EditorCommand::MoveLeft => app.move_left(),
             EditorCommand::MoveDown => app.move_down(ctx.height),
             EditorCommand::MoveUp => app.move_up(),
@@ -127,54 +172,93 @@ impl EditorCommand {
                 app.selection_start = None;
             }
             EditorCommand::ExitHelp => app.mode = Mode::Normal,
-            EditorCommand::ExitCommand => app.mode = Mode::Normal,
+            EditorCommand::ExitCommand => {
+                app.mode = Mode::Normal;
+                app.selection_start = None;
+            }
             EditorCommand::CommandSubmit => {
                 let cmd = if let Mode::Command(ref mut c) = app.mode {
                     c.trim().to_string()
                 } else {
                     String::new()
                 };
-                match cmd.as_str() {
+                let mut parts = cmd.splitn(2, char::is_whitespace);
+                let name = parts.next().unwrap_or("");
+                let args = parts.next().unwrap_or("").trim();
+                match name {
                     "q" => return true,
                     "help" => app.mode = Mode::Help,
+                    "set" => {
+                        if args.is_empty() {
+                            app.message = Some(app.options.summary());
+                        } else if let Err(err) = app.options.apply(args) {
+                            app.message = Some(err);
+                        }
+                        // the filter may now keep other lines
+                        app.relayout(ctx.height, App::refresh_search_hits);
+                        app.mode = Mode::Normal;
+                    }
+                    "filter" | "filter!" => {
+                        app.set_filter(args, name == "filter!", ctx.height);
+                        app.mode = Mode::Normal;
+                    }
+                    "lines" => app.open_finder(args),
+                    "hl" => {
+                        let (sub, rest) = args.split_once(' ').unwrap_or((args, ""));
+                        match sub {
+                            "" => app.list_highlights(),
+                            "add" => app.add_highlight(rest),
+                            "remove" => app.remove_highlight(rest.trim()),
+                            "clear" => app.highlights.clear(),
+                            _ => {
+                                app.message = Some(
+                                    "Usage: hl [add PATTERN COLOR|remove PATTERN|clear]"
+                                        .to_string(),
+                                )
+                            }
+                        }
+                        app.mode = Mode::Normal;
+                    }
+                    "export-review" => {
+                        app.export_review(args);
+                        app.mode = Mode::Normal;
+                    }
+                    "annotations" => {
+                        match args {
+                            "save" => app.save_annotations(),
+                            "load" => app.load_annotations(),
+                            _ => app.message = Some("Usage: annotations save|load".to_string()),
+                        }
+                        app.mode = Mode::Normal;
+                    }
                     _ => {
-                        let mut parts = cmd.splitn(2, char::is_whitespace);
-                        let name = parts.next().unwrap_or("");
-                        let args = parts.next().unwrap_or("");
                         if let Some(spec) = app.commands.get(name) {
-                            let mut template = spec.template.clone();
-                            template = template.replace("{line}", &(app.cursor_y + 1).to_string());
-                            template = template.replace("{col}", &(app.cursor_x + 1).to_string());
-                            template = template.replace("{args}", args);
-                            let ((sy, sx), (ey, ex)) = match app.selection_start {
-                                Some(start) => {
-                                    let end = (app.cursor_y, app.cursor_x);
-                                    if start <= end {
-                                        (start, end)
-                                    } else {
-                                        (end, start)
-                                    }
-                                }
-                                None => {
-                                    let pos = (app.cursor_y, app.cursor_x);
-                                    (pos, pos)
+                            let (_, (ey, _)) = app.selection_bounds();
+                            let ey = app.source_line(ey);
+                            let argv = spec.argv(&|placeholder| app.placeholder(placeholder, args));
+                            let argv = match argv {
+                                Ok(argv) => argv,
+                                Err(err) => {
+                                    app.message = Some(err);
+                                    Vec::new()
                                 }
                             };
-                            template = template.replace("{start_line}", &(sy + 1).to_string());
-                            template = template.replace("{start_col}", &(sx + 1).to_string());
-                            template = template.replace("{end_line}", &(ey + 1).to_string());
-                            template = template.replace("{end_col}", &(ex + 1).to_string());
 
-                            let parts: Vec<&str> = template.split_whitespace().collect();
-                            if let Some((prog, rest)) = parts.split_first() {
-                                let mut command = std::process::Command::new(prog);
-                                command.args(rest);
-                                let _ = command.status();
+                            if !argv.is_empty() {
+                                let input = spec.stdin.then(|| app.piped_input());
+                                let name = spec.name.clone();
+                                if spec.foreground {
+                                    app.foreground = Some(ForegroundCommand { name, argv, input });
+                                } else {
+                                    let capture_after = spec.capture.then_some(ey);
+                                    app.start_job(&name, &argv, input, capture_after);
+                                }
                             }
                         }
                         app.mode = Mode::Normal;
                     }
                 }
+                app.selection_start = None;
             }
             EditorCommand::CommandBackspace => {
                 if let Mode::Command(ref mut c) = app.mode {
@@ -186,16 +270,17 @@ impl EditorCommand {
                     c.push(ch);
                 }
             }
-            EditorCommand::ExitSearch => app.mode = Mode::Normal,
+            EditorCommand::ExitSearch => {
+                app.cancel_search();
+                app.mode = Mode::Normal;
+            }
             EditorCommand::ClearSearch => {
+                app.cancel_search();
                 app.clear_search();
                 app.mode = Mode::Normal;
             }
             EditorCommand::SearchSubmit => {
-                if let Mode::Search(ref mut q) = app.mode {
-                    let q2 = q.clone();
-                    app.set_search_query(q2);
-                }
+                app.submit_search();
                 app.mode = Mode::Normal;
                 app.ensure_visible(ctx.height);
             }
@@ -203,12 +288,59 @@ impl EditorCommand {
                 if let Mode::Search(ref mut q) = app.mode {
                     q.pop();
                 }
+                app.history.reset();
+                app.preview_search(ctx.height);
             }
             EditorCommand::SearchChar(ch) => {
                 if let Mode::Search(ref mut q) = app.mode {
                     q.push(ch);
                 }
+                app.history.reset();
+                app.preview_search(ctx.height);
+            }
+            EditorCommand::SearchHistoryPrev => app.recall_search(true, ctx.height),
+            EditorCommand::SearchHistoryNext => app.recall_search(false, ctx.height),
+            EditorCommand::CancelJob => app.cancel_job(),
+            EditorCommand::OpenFinder => {
+                app.open_finder("");
+                ctx.pending_g = false;
+            }
+            EditorCommand::CloseFinder => app.mode = Mode::Normal,
+            EditorCommand::FinderSubmit => app.submit_finder(ctx.height),
+            EditorCommand::FinderNext => app.move_finder_selection(true),
+            EditorCommand::FinderPrev => app.move_finder_selection(false),
+            EditorCommand::FinderBackspace => {
+                if let Mode::Finder(ref mut finder) = app.mode {
+                    finder.query.pop();
+                }
+                app.update_finder();
+            }
+            EditorCommand::FinderChar(ch) => {
+                if let Mode::Finder(ref mut finder) = app.mode {
+                    finder.query.push(ch);
+                }
+                app.update_finder();
+            }
+            EditorCommand::StartNote => {
+                app.start_note();
+                app.ensure_visible(ctx.height);
+                ctx.pending_g = false;
             }
+            EditorCommand::DeleteOverlay => app.delete_overlay(ctx.height),
+            EditorCommand::SaveNote => {
+                app.finish_note();
+                app.ensure_visible(ctx.height);
+            }
+            EditorCommand::CancelNote => {
+                app.cancel_note();
+                app.ensure_visible(ctx.height);
+            }
+            EditorCommand::NoteNewline => {
+                app.note_newline();
+                app.ensure_visible(ctx.height);
+            }
+            EditorCommand::NoteBackspace => app.note_backspace(),
+            EditorCommand::NoteChar(ch) => app.note_char(ch),
         }
         false
     }
@@ -220,6 +352,14 @@ pub struct KeyBinding {
     pub help: &'static str,
 }
 
+/// A binding triggered by `prefix` followed by `key`.
+pub struct PrefixBinding {
+    pub prefix: char,
+    pub key: KeyEvent,
+    pub command: EditorCommand,
+    pub help: &'static str,
+}
+
 pub const NORMAL_BINDINGS: &[KeyBinding] = &[
     KeyBinding {
         key: KeyEvent::new(KeyCode::Char('v'), KeyModifiers::NONE),
@@ -237,7 +377,7 @@ pub const NORMAL_BINDINGS: &[KeyBinding] = &[
         help: "gg goto first line",
     },
     KeyBinding {
-        key: KeyEvent::new(KeyCode::Char('?'), KeyModifiers::NONE),
+        key: KeyEvent::new(KeyCode::F(1), KeyModifiers::NONE),
         command: EditorCommand::EnterHelp,
         help: "Show this help",
     },
@@ -251,6 +391,11 @@ pub const NORMAL_BINDINGS: &[KeyBinding] = &[
         command: EditorCommand::EnterSearch,
         help: "Search",
     },
+    KeyBinding {
+        key: KeyEvent::new(KeyCode::Char('?'), KeyModifiers::NONE),
+        command: EditorCommand::EnterSearchBackward,
+        help: "Search backward",
+    },
     KeyBinding {
         key: KeyEvent::new(KeyCode::Char('n'), KeyModifiers::NONE),
         command: EditorCommand::NextHit,
@@ -336,6 +481,75 @@ pub const NORMAL_BINDINGS: &[KeyBinding] = &[
         command: EditorCommand::CursorBottom,
         help: "Bottom of screen",
     },
+    KeyBinding {
+        key: KeyEvent::new(KeyCode::Char('a'), KeyModifiers::NONE),
+        command: EditorCommand::StartNote,
+        help: "Add or edit note",
+    },
+    KeyBinding {
+        key: KeyEvent::new(KeyCode::Char('&'), KeyModifiers::NONE),
+        command: EditorCommand::EnterFilter,
+        help: "Filter lines",
+    },
+    KeyBinding {
+        key: KeyEvent::new(KeyCode::Char('*'), KeyModifiers::NONE),
+        command: EditorCommand::SearchWordForward,
+        help: "Search word under cursor",
+    },
+    KeyBinding {
+        key: KeyEvent::new(KeyCode::Char('#'), KeyModifiers::NONE),
+        command: EditorCommand::SearchWordBackward,
+        help: "Search word under cursor backward",
+    },
+    KeyBinding {
+        key: KeyEvent::new(KeyCode::Char('p'), KeyModifiers::CONTROL),
+        command: EditorCommand::OpenFinder,
+        help: "Find line",
+    },
+    KeyBinding {
+        key: KeyEvent::new(KeyCode::Char('c'), KeyModifiers::CONTROL),
+        command: EditorCommand::CancelJob,
+        help: "Cancel running command",
+    },
+];
+
+pub const NORMAL_PREFIX_BINDINGS: &[PrefixBinding] = &[
+    PrefixBinding {
+        prefix: ']',
+        key: KeyEvent::new(KeyCode::Char('o'), KeyModifiers::NONE),
+        command: EditorCommand::NextOverlay,
+        help: "Next overlay",
+    },
+    PrefixBinding {
+        prefix: '[',
+        key: KeyEvent::new(KeyCode::Char('o'), KeyModifiers::NONE),
+        command: EditorCommand::PrevOverlay,
+        help: "Prev overlay",
+    },
+    PrefixBinding {
+        prefix: 'z',
+        key: KeyEvent::new(KeyCode::Char('a'), KeyModifiers::NONE),
+        command: EditorCommand::ToggleOverlay,
+        help: "Toggle overlay",
+    },
+    PrefixBinding {
+        prefix: 'z',
+        key: KeyEvent::new(KeyCode::Char('R'), KeyModifiers::NONE),
+        command: EditorCommand::ExpandAllOverlays,
+        help: "Expand all overlays",
+    },
+    PrefixBinding {
+        prefix: 'z',
+        key: KeyEvent::new(KeyCode::Char('M'), KeyModifiers::NONE),
+        command: EditorCommand::CollapseAllOverlays,
+        help: "Collapse all overlays",
+    },
+    PrefixBinding {
+        prefix: 'd',
+        key: KeyEvent::new(KeyCode::Char('o'), KeyModifiers::NONE),
+        command: EditorCommand::DeleteOverlay,
+        help: "Delete overlay",
+    },
 ];
 
 pub const VISUAL_BINDINGS: &[KeyBinding] = &[
@@ -344,13 +558,18 @@ pub const VISUAL_BINDINGS: &[KeyBinding] = &[
         command: EditorCommand::CancelSelection,
         help: "Cancel selection",
     },
+    KeyBinding {
+        key: KeyEvent::new(KeyCode::Char('a'), KeyModifiers::NONE),
+        command: EditorCommand::StartNote,
+        help: "Add note after selection",
+    },
     KeyBinding {
         key: KeyEvent::new(KeyCode::Char('q'), KeyModifiers::NONE),
         command: EditorCommand::Quit,
         help: "Quit",
     },
     KeyBinding {
-        key: KeyEvent::new(KeyCode::Char('?'), KeyModifiers::NONE),
+        key: KeyEvent::new(KeyCode::F(1), KeyModifiers::NONE),
         command: EditorCommand::EnterHelp,
         help: "Show this help",
     },
@@ -419,6 +638,11 @@ pub const VISUAL_BINDINGS: &[KeyBinding] = &[
         command: EditorCommand::CursorBottom,
         help: "Bottom of screen",
     },
+    KeyBinding {
+        key: KeyEvent::new(KeyCode::Char(':'), KeyModifiers::NONE),
+        command: EditorCommand::EnterCommand,
+        help: "Run command on selection",
+    },
 ];
 
 pub const COMMAND_BINDINGS: &[KeyBinding] = &[
@@ -465,6 +689,82 @@ pub const SEARCH_BINDINGS: &[KeyBinding] = &[
         command: EditorCommand::SearchBackspace,
         help: "Delete char",
     },
+    KeyBinding {
+        key: KeyEvent::new(KeyCode::Up, KeyModifiers::NONE),
+        command: EditorCommand::SearchHistoryPrev,
+        help: "Older search",
+    },
+    KeyBinding {
+        key: KeyEvent::new(KeyCode::Down, KeyModifiers::NONE),
+        command: EditorCommand::SearchHistoryNext,
+        help: "Newer search",
+    },
+];
+
+pub const FINDER_BINDINGS: &[KeyBinding] = &[
+    KeyBinding {
+        key: KeyEvent::new(KeyCode::Esc, KeyModifiers::NONE),
+        command: EditorCommand::CloseFinder,
+        help: "Close finder",
+    },
+    KeyBinding {
+        key: KeyEvent::new(KeyCode::Char('c'), KeyModifiers::CONTROL),
+        command: EditorCommand::CloseFinder,
+        help: "Close finder",
+    },
+    KeyBinding {
+        key: KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE),
+        command: EditorCommand::FinderSubmit,
+        help: "Go to line",
+    },
+    KeyBinding {
+        key: KeyEvent::new(KeyCode::Backspace, KeyModifiers::NONE),
+        command: EditorCommand::FinderBackspace,
+        help: "Delete char",
+    },
+    KeyBinding {
+        key: KeyEvent::new(KeyCode::Down, KeyModifiers::NONE),
+        command: EditorCommand::FinderNext,
+        help: "Next match",
+    },
+    KeyBinding {
+        key: KeyEvent::new(KeyCode::Up, KeyModifiers::NONE),
+        command: EditorCommand::FinderPrev,
+        help: "Previous match",
+    },
+    KeyBinding {
+        key: KeyEvent::new(KeyCode::Char('n'), KeyModifiers::CONTROL),
+        command: EditorCommand::FinderNext,
+        help: "Next match",
+    },
+    KeyBinding {
+        key: KeyEvent::new(KeyCode::Char('p'), KeyModifiers::CONTROL),
+        command: EditorCommand::FinderPrev,
+        help: "Previous match",
+    },
+];
+
+pub const NOTE_BINDINGS: &[KeyBinding] = &[
+    KeyBinding {
+        key: KeyEvent::new(KeyCode::Esc, KeyModifiers::NONE),
+        command: EditorCommand::SaveNote,
+        help: "Save note",
+    },
+    KeyBinding {
+        key: KeyEvent::new(KeyCode::Char('c'), KeyModifiers::CONTROL),
+        command: EditorCommand::CancelNote,
+        help: "Cancel note",
+    },
+    KeyBinding {
+        key: KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE),
+        command: EditorCommand::NoteNewline,
+        help: "New line",
+    },
+    KeyBinding {
+        key: KeyEvent::new(KeyCode::Backspace, KeyModifiers::NONE),
+        command: EditorCommand::NoteBackspace,
+        help: "Delete char",
+    },
 ];
 
 pub const HELP_BINDINGS: &[KeyBinding] = &[
@@ -495,6 +795,35 @@ pub fn lookup_and_run(
     false
 }
 
+/// Handles a key for prefixed bindings: starts a sequence when `key` is a
+/// known prefix, or completes a pending one. Returns `None` when the key is
+/// not part of a prefixed binding.
+pub fn lookup_prefixed(
+    bindings: &[PrefixBinding],
+    key: KeyEvent,
+    app: &mut App,
+    ctx: &mut Context,
+) -> Option<bool> {
+    if let Some(prefix) = ctx.pending_prefix.take() {
+        ctx.pending_g = false;
+        return Some(
+            bindings
+                .iter()
+                .find(|b| b.prefix == prefix && b.key == key)
+                .is_some_and(|b| b.command.run(app, ctx)),
+        );
+    }
+    if let KeyCode::Char(c) = key.code
+        && key.modifiers == KeyModifiers::NONE
+        && bindings.iter().any(|b| b.prefix == c)
+    {
+        ctx.pending_prefix = Some(c);
+        ctx.pending_g = false;
+        return Some(false);
+    }
+    None
+}
+
 fn format_key(key: KeyEvent) -> String {
     use KeyCode::*;
     let mut parts = Vec::new();
@@ -509,6 +838,7 @@ fn format_key(key: KeyEvent) -> String {
         Enter => "Enter".to_string(),
         Esc => "Esc".to_string(),
         Backspace => "Backspace".to_string(),
+        F(n) => format!("F{n}"),
         _ => format!("{:?}", key.code),
     };
     parts.push(code);
@@ -522,6 +852,14 @@ pub fn help_lines(commands: &[CommandSpec]) -> Vec<String> {
     for binding in NORMAL_BINDINGS {
         lines.push(format!("{} - {}", format_key(binding.key), binding.help));
     }
+    for binding in NORMAL_PREFIX_BINDINGS {
+        lines.push(format!(
+            "{}{} - {}",
+            binding.prefix,
+            format_key(binding.key),
+            binding.help
+        ));
+    }
     lines.push(String::new());
 
     lines.push("Visual mode:".to_string());
@@ -542,6 +880,18 @@ pub fn help_lines(commands: &[CommandSpec]) -> Vec<String> {
     }
     lines.push(String::new());
 
+    lines.push("Line finder:".to_string());
+    for binding in FINDER_BINDINGS {
+        lines.push(format!("{} - {}", format_key(binding.key), binding.help));
+    }
+    lines.push(String::new());
+
+    lines.push("Note mode:".to_string());
+    for binding in NOTE_BINDINGS {
+        lines.push(format!("{} - {}", format_key(binding.key), binding.help));
+    }
+    lines.push(String::new());
+
     lines.push("Help screen:".to_string());
     for binding in HELP_BINDINGS {
         lines.push(format!("{} - {}", format_key(binding.key), binding.help));
@@ -551,7 +901,20 @@ pub fn help_lines(commands: &[CommandSpec]) -> Vec<String> {
         lines.push(String::new());
         lines.push("Custom commands:".to_string());
         for cmd in commands {
-            lines.push(format!(":{} - {}", cmd.name, cmd.template));
+            let mut suffix = String::new();
+            if cmd.shell {
+                suffix.push_str(" (sh)");
+            }
+            if cmd.stdin {
+                suffix.push_str(" (stdin)");
+            }
+            if cmd.foreground {
+                suffix.push_str(" (foreground)");
+            }
+            if cmd.capture {
+                suffix.push_str(" (inline)");
+            }
+            lines.push(format!(":{} - {}{}", cmd.name, cmd.template, suffix));
         }
     }
 
@@ -0,0 +1,123 @@
+use std::path::Path;
+
+use crate::OverlayItem;
+
+/// Review report formats for `:export-review`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReviewFormat {
+    Markdown,
+    /// The file as a unified diff that adds each annotation as comment lines.
+    Diff,
+}
+
+impl ReviewFormat {
+    /// Picks the format from the output path: `.diff` and `.patch` files get a
+    /// diff, everything else Markdown.
+    pub fn for_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("diff" | "patch") => ReviewFormat::Diff,
+            _ => ReviewFormat::Markdown,
+        }
+    }
+}
+
+/// Groups overlays by the document line they follow, in display order.
+fn overlays_after(overlays: &[OverlayItem], line_count: usize) -> Vec<(usize, Vec<&OverlayItem>)> {
+    let mut groups: Vec<(usize, Vec<&OverlayItem>)> = Vec::new();
+    for o in overlays {
+        let line = o.after_line.min(line_count.saturating_sub(1));
+        match groups.last_mut() {
+            Some((l, items)) if *l == line => items.push(o),
+            _ => groups.push((line, vec![o])),
+        }
+    }
+    groups
+}
+
+pub fn render(
+    format: ReviewFormat,
+    path: &Path,
+    lines: &[String],
+    overlays: &[OverlayItem],
+) -> String {
+    match format {
+        ReviewFormat::Markdown => markdown(path, lines, overlays),
+        ReviewFormat::Diff => diff(path, lines, overlays),
+    }
+}
+
+/// The file in fenced code blocks with each annotation as a quote after the
+/// line it refers to.
+fn markdown(path: &Path, lines: &[String], overlays: &[OverlayItem]) -> String {
+    let width = lines.len().max(1).to_string().len();
+    let mut out = format!("# Review of `{}`\n", path.display());
+    let mut start = 0;
+    for (line, items) in overlays_after(overlays, lines.len()) {
+        out.push_str("\n```\n");
+        for (i, text) in lines.iter().enumerate().take(line + 1).skip(start) {
+            out.push_str(&format!("{:>width$} | {}\n", i + 1, text));
+        }
+        out.push_str("```\n");
+        for o in items {
+            out.push_str(&format!("\n> **{}** (line {}):\n", o.kind.name(), line + 1));
+            for text in &o.content {
+                out.push_str(&format!("> {text}\n"));
+            }
+        }
+        start = line + 1;
+    }
+    if start < lines.len() {
+        out.push_str("\n```\n");
+        for (i, text) in lines.iter().enumerate().skip(start) {
+            out.push_str(&format!("{:>width$} | {}\n", i + 1, text));
+        }
+        out.push_str("```\n");
+    }
+    out
+}
+
+/// A single hunk keeping every line as context and adding annotations as
+/// `+` lines. Annotations on an empty file make up the whole hunk.
+fn diff(path: &Path, lines: &[String], overlays: &[OverlayItem]) -> String {
+    let added: usize = overlays.iter().map(|o| o.content.len()).sum();
+    // unified diffs number an empty side from 0
+    let start = |len: usize| usize::from(len > 0);
+    let mut out = format!(
+        "--- a/{0}\n+++ b/{0}\n@@ -{1},{2} +{3},{4} @@\n",
+        path.display(),
+        start(lines.len()),
+        lines.len(),
+        start(lines.len() + added),
+        lines.len() + added
+    );
+    let annotate = |out: &mut String, line: usize, items: Vec<&OverlayItem>| {
+        for o in items {
+            for (j, text) in o.content.iter().enumerate() {
+                if j == 0 {
+                    out.push_str(&format!(
+                        "+> {} (line {}): {}\n",
+                        o.kind.name(),
+                        line + 1,
+                        text
+                    ));
+                } else {
+                    out.push_str(&format!("+> {text}\n"));
+                }
+            }
+        }
+    };
+    let mut groups = overlays_after(overlays, lines.len()).into_iter().peekable();
+    if lines.is_empty() {
+        for (line, items) in groups {
+            annotate(&mut out, line, items);
+        }
+        return out;
+    }
+    for (i, text) in lines.iter().enumerate() {
+        out.push_str(&format!(" {text}\n"));
+        if let Some((_, items)) = groups.next_if(|(l, _)| *l == i) {
+            annotate(&mut out, i, items);
+        }
+    }
+    out
+}
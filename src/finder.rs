@@ -0,0 +1,54 @@
+/// Most results the line finder keeps; more than fit on any screen.
+pub const MAX_RESULTS: usize = 500;
+
+/// How well a line matched the finder query.
+pub struct FuzzyMatch {
+    pub score: i64,
+    /// Byte offsets of the matched characters, for highlighting.
+    pub positions: Vec<usize>,
+}
+
+fn starts_word(line: &str, at: usize) -> bool {
+    line[..at]
+        .chars()
+        .next_back()
+        .is_none_or(|c| !c.is_alphanumeric())
+}
+
+/// Matches `query` against `line` when every query character appears in
+/// order, ignoring ASCII case. Consecutive characters and characters at the
+/// start of a word score higher; skipped characters cost a little.
+pub fn fuzzy_match(query: &str, line: &str) -> Option<FuzzyMatch> {
+    let mut positions = Vec::with_capacity(query.len());
+    let mut chars = line.char_indices();
+    let mut score = 0;
+    let mut last: Option<usize> = None;
+    for q in query.chars() {
+        let (at, _) = chars.find(|(_, c)| c.eq_ignore_ascii_case(&q))?;
+        score += 16;
+        match last {
+            Some(prev) => match line[prev..at].chars().count() - 1 {
+                0 => score += 16,
+                gap => score -= gap as i64,
+            },
+            None => score -= at.min(16) as i64,
+        }
+        if starts_word(line, at) {
+            score += 8;
+        }
+        positions.push(at);
+        last = Some(at);
+    }
+    Some(FuzzyMatch { score, positions })
+}
+
+/// Indices of the `lines` matching `query`, best first. Equal scores keep
+/// document order, so an empty query lists every line.
+pub fn rank<'a>(query: &str, lines: impl Iterator<Item = (usize, &'a str)>) -> Vec<usize> {
+    let mut scored: Vec<(i64, usize)> = lines
+        .filter_map(|(i, line)| fuzzy_match(query, line).map(|m| (m.score, i)))
+        .collect();
+    scored.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+    scored.truncate(MAX_RESULTS);
+    scored.into_iter().map(|(_, i)| i).collect()
+}
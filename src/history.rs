@@ -0,0 +1,103 @@
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Most queries kept on disk.
+const MAX_ENTRIES: usize = 100;
+
+/// Submitted search queries, oldest first, with Vim-style recall: Up and Down
+/// step through entries that start with what was typed before recalling.
+#[derive(Default)]
+pub struct SearchHistory {
+    entries: Vec<String>,
+    /// File the history is saved to; `None` keeps it in memory only.
+    path: Option<PathBuf>,
+    /// Entry currently recalled and the prefix being matched.
+    recall: Option<(usize, String)>,
+}
+
+/// `$XDG_STATE_HOME/file-viewer/search_history`, falling back to
+/// `~/.local/state`.
+pub fn default_path() -> Option<PathBuf> {
+    let state = env::var_os("XDG_STATE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".local/state")))?;
+    Some(state.join("file-viewer").join("search_history"))
+}
+
+impl SearchHistory {
+    /// Reads the history saved at `path`; a missing file gives an empty
+    /// history that will be created on the first search.
+    pub fn load(path: PathBuf) -> Self {
+        let entries = fs::read_to_string(&path)
+            .map(|s| s.lines().map(|l| l.to_string()).collect())
+            .unwrap_or_default();
+        Self {
+            entries,
+            path: Some(path),
+            recall: None,
+        }
+    }
+
+    /// Records a submitted query, moving repeats to the end, and saves the
+    /// history.
+    pub fn push(&mut self, query: &str) -> io::Result<()> {
+        self.recall = None;
+        if query.is_empty() {
+            return Ok(());
+        }
+        self.entries.retain(|e| e != query);
+        self.entries.push(query.to_string());
+        let excess = self.entries.len().saturating_sub(MAX_ENTRIES);
+        self.entries.drain(..excess);
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut contents = self.entries.join("\n");
+        contents.push('\n');
+        fs::write(path, contents)
+    }
+
+    /// Forgets the recall position, e.g. after the query is edited.
+    pub fn reset(&mut self) {
+        self.recall = None;
+    }
+
+    /// Older entry starting with the typed prefix, if any.
+    pub fn older(&mut self, typed: &str) -> Option<String> {
+        let (pos, prefix) = self
+            .recall
+            .take()
+            .unwrap_or((self.entries.len(), typed.to_string()));
+        let found = self.entries[..pos]
+            .iter()
+            .rposition(|e| e.starts_with(&prefix));
+        self.recall = Some((found.unwrap_or(pos), prefix));
+        found.map(|i| self.entries[i].clone())
+    }
+
+    /// Newer entry starting with the typed prefix, or the prefix itself once
+    /// past the newest entry.
+    pub fn newer(&mut self) -> Option<String> {
+        let (pos, prefix) = self.recall.take()?;
+        let found = self
+            .entries
+            .iter()
+            .enumerate()
+            .skip(pos + 1)
+            .find(|(_, e)| e.starts_with(&prefix))
+            .map(|(i, _)| i);
+        match found {
+            Some(i) => {
+                self.recall = Some((i, prefix));
+                Some(self.entries[i].clone())
+            }
+            None => Some(prefix),
+        }
+    }
+}
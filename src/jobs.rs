@@ -0,0 +1,150 @@
+use std::io::{self, Read, Write};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+const SPINNER: &[char] = &['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'];
+
+/// How often a job's thread checks whether its process has exited.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// A custom command running in the background. The process is shared with
+/// the thread waiting on it so it can be killed from the UI.
+pub struct Job {
+    pub id: usize,
+    pub name: String,
+    /// Document line to show captured stdout after, or `None` to discard it.
+    pub capture_after: Option<usize>,
+    child: Arc<Mutex<Child>>,
+    started: Instant,
+}
+
+/// Sent to the main loop once a job's process has exited.
+pub struct JobDone {
+    pub id: usize,
+    pub status: io::Result<ExitStatus>,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+/// Writes `input` to the child's stdin from another thread, so a child that
+/// answers before reading all of its input can't deadlock on a full pipe.
+fn feed_stdin(child: &mut Child, input: Option<String>) {
+    if let (Some(mut stdin), Some(input)) = (child.stdin.take(), input) {
+        thread::spawn(move || {
+            // a child that exits without reading everything is not an error
+            let _ = stdin.write_all(input.as_bytes());
+        });
+    }
+}
+
+fn read_all(pipe: Option<impl Read + Send + 'static>) -> Option<JoinHandle<String>> {
+    pipe.map(|mut pipe| {
+        thread::spawn(move || {
+            let mut bytes = Vec::new();
+            let _ = pipe.read_to_end(&mut bytes);
+            String::from_utf8_lossy(&bytes).into_owned()
+        })
+    })
+}
+
+impl Job {
+    /// Starts `argv`, writing `input` to its stdin when given, and reports
+    /// on `done` when it exits. Stdout is only kept when `capture_after` is
+    /// set; stderr is always kept for error messages.
+    pub fn spawn(
+        id: usize,
+        name: &str,
+        argv: &[String],
+        input: Option<String>,
+        capture_after: Option<usize>,
+        done: mpsc::Sender<JobDone>,
+    ) -> io::Result<Self> {
+        let Some((program, args)) = argv.split_first() else {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "empty command"));
+        };
+        let mut command = Command::new(program);
+        command.args(args);
+        command.stdin(match input {
+            Some(_) => Stdio::piped(),
+            None => Stdio::null(),
+        });
+        command.stdout(match capture_after {
+            Some(_) => Stdio::piped(),
+            None => Stdio::null(),
+        });
+        command.stderr(Stdio::piped());
+        // a group of its own, so cancelling also stops what a shell started
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(&mut command, 0);
+        let mut child = command.spawn()?;
+        feed_stdin(&mut child, input);
+        let stdout = read_all(child.stdout.take());
+        let stderr = read_all(child.stderr.take());
+        let child = Arc::new(Mutex::new(child));
+        let waited = Arc::clone(&child);
+        thread::spawn(move || {
+            let status = loop {
+                let status = waited.lock().map_or(Ok(None), |mut c| c.try_wait());
+                match status {
+                    Ok(Some(status)) => break Ok(status),
+                    Ok(None) => thread::sleep(POLL_INTERVAL),
+                    Err(err) => break Err(err),
+                }
+            };
+            let join =
+                |h: Option<JoinHandle<String>>| h.and_then(|h| h.join().ok()).unwrap_or_default();
+            let _ = done.send(JobDone {
+                id,
+                status,
+                stdout: join(stdout),
+                stderr: join(stderr),
+            });
+        });
+
+        Ok(Self {
+            id,
+            name: name.to_string(),
+            capture_after,
+            child,
+            started: Instant::now(),
+        })
+    }
+
+    /// Kills the process and everything it started; its thread still
+    /// reports it as done.
+    pub fn cancel(&self) {
+        if let Ok(mut child) = self.child.lock() {
+            // the group outlives the child when a shell left commands running
+            #[cfg(unix)]
+            // SAFETY: `kill` only sends a signal; the group id is the child's pid
+            unsafe {
+                libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+            }
+            let _ = child.kill();
+        }
+    }
+
+    /// The spinner frame for how long the job has been running.
+    pub fn spinner(&self) -> char {
+        let frame = self.started.elapsed().as_millis() / 100;
+        SPINNER[frame as usize % SPINNER.len()]
+    }
+}
+
+/// Runs `argv` attached to the terminal and waits for it, writing `input` to
+/// its stdin when given. The caller hands over the terminal first.
+pub fn run_foreground(argv: &[String], input: Option<String>) -> io::Result<ExitStatus> {
+    let Some((program, args)) = argv.split_first() else {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "empty command"));
+    };
+    let mut command = Command::new(program);
+    command.args(args);
+    if input.is_some() {
+        command.stdin(Stdio::piped());
+    }
+    let mut child = command.spawn()?;
+    feed_stdin(&mut child, input);
+    child.wait()
+}
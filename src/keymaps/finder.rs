@@ -0,0 +1,15 @@
+use ratatui::crossterm::event::KeyEvent;
+
+use crate::App;
+use crate::commands::{Context, EditorCommand, FINDER_BINDINGS, lookup_and_run};
+
+pub fn handle(app: &mut App, key: KeyEvent, ctx: &mut Context) -> bool {
+    if let ratatui::crossterm::event::KeyCode::Char(c) = key.code {
+        if FINDER_BINDINGS.iter().any(|b| b.key == key) {
+            return lookup_and_run(FINDER_BINDINGS, key, app, ctx);
+        } else {
+            return EditorCommand::FinderChar(c).run(app, ctx);
+        }
+    }
+    lookup_and_run(FINDER_BINDINGS, key, app, ctx)
+}
@@ -1,5 +1,7 @@
 pub mod command;
+pub mod finder;
 pub mod help;
 pub mod normal;
+pub mod note;
 pub mod search;
 pub mod visual;
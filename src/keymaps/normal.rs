@@ -1,8 +1,13 @@
 use ratatui::crossterm::event::KeyEvent;
 
 use crate::App;
-use crate::commands::{Context, NORMAL_BINDINGS, lookup_and_run};
+use crate::commands::{
+    Context, NORMAL_BINDINGS, NORMAL_PREFIX_BINDINGS, lookup_and_run, lookup_prefixed,
+};
 
 pub fn handle(app: &mut App, key: KeyEvent, ctx: &mut Context) -> bool {
+    if let Some(quit) = lookup_prefixed(NORMAL_PREFIX_BINDINGS, key, app, ctx) {
+        return quit;
+    }
     lookup_and_run(NORMAL_BINDINGS, key, app, ctx)
 }
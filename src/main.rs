@@ -9,13 +9,26 @@ use ratatui::{
         terminal::{EnterAlternateScreen, LeaveAlternateScreen, disable_raw_mode, enable_raw_mode},
     },
     prelude::*,
-    widgets::{Paragraph, Wrap},
+    widgets::{Block, Clear, Paragraph, Wrap},
 };
+mod annotations;
 mod command_spec;
 mod commands;
+mod export;
+mod finder;
+mod history;
+mod jobs;
 mod keymaps;
+mod options;
+mod overlay;
+mod search;
 use command_spec::CommandSpec;
+use history::SearchHistory;
+use jobs::{Job, JobDone};
 use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use options::Options;
+use overlay::OverlayKind;
+use search::{Filter, Highlight, Pattern};
 use std::collections::HashMap;
 use std::{
     fs::File,
@@ -32,26 +45,29 @@ fn is_keyword(b: u8) -> bool {
 fn highlight_line<'a>(
     line: &'a str,
     line_idx: usize,
-    query: Option<&str>,
+    overlay: Option<(OverlayKind, usize)>,
+    highlights: &[Highlight],
+    pattern: Option<&Pattern>,
     selection: Option<((usize, usize), (usize, usize))>,
     line_mode: bool,
 ) -> Line<'a> {
     let bytes = line.as_bytes();
-    let mut styles = vec![Style::default(); bytes.len()];
-
-    if let Some(q) = query {
-        if !q.is_empty() {
-            let mut start = 0;
-            while let Some(pos) = line[start..].find(q) {
-                for i in start + pos..start + pos + q.len() {
-                    if i < styles.len() {
-                        styles[i] = styles[i].bg(Color::Yellow);
-                    }
-                }
-
-                // search results are highlighted via styles; spans are built later
+    let base = overlay.map(|(kind, _)| kind.style()).unwrap_or_default();
+    let mut styles = vec![base; bytes.len()];
+
+    // later pins win where they overlap; the search is drawn over all of them
+    for highlight in highlights {
+        for (start, end) in highlight.pattern.matches(line) {
+            for style in &mut styles[start..end] {
+                *style = style.bg(highlight.color);
+            }
+        }
+    }
 
-                start += pos + q.len();
+    if let Some(pattern) = pattern {
+        for (start, end) in pattern.matches(line) {
+            for style in &mut styles[start..end] {
+                *style = style.bg(Color::Yellow);
             }
         }
     }
@@ -87,11 +103,17 @@ fn highlight_line<'a>(
         }
     }
 
-    if styles.is_empty() {
+    let mut spans = Vec::new();
+    if let Some((kind, hidden)) = overlay {
+        let border = if hidden > 0 { '\u{25b8}' } else { '\u{2502}' };
+        spans.push(Span::styled(
+            format!("{}{} ", kind.marker(), border),
+            kind.gutter_style(),
+        ));
+    } else if styles.is_empty() {
         return Line::from(line.to_owned());
     }
 
-    let mut spans = Vec::new();
     let mut i = 0;
     while i < bytes.len() {
         let mut j = i + 1;
@@ -103,6 +125,15 @@ fn highlight_line<'a>(
         i = j;
     }
 
+    if let Some((kind, hidden)) = overlay
+        && hidden > 0
+    {
+        spans.push(Span::styled(
+            format!(" (+{hidden} lines)"),
+            kind.gutter_style(),
+        ));
+    }
+
     Line::from(spans)
 }
 
@@ -114,6 +145,45 @@ enum Mode {
     Command(String),
     Search(String),
     Help,
+    Note(NoteDraft),
+    Finder(LineFinder),
+}
+
+/// State saved when entering search mode so Esc can undo the incremental
+/// search preview.
+struct SearchOrigin {
+    backward: bool,
+    cursor: (usize, usize),
+    scroll: u16,
+    pattern: Option<Pattern>,
+    hits: Vec<(usize, usize)>,
+    current_hit: Option<usize>,
+}
+
+/// A note being written or edited in place as an overlay.
+#[derive(Clone)]
+struct NoteDraft {
+    /// Id of the overlay holding the draft.
+    id: usize,
+    /// Content before editing started, or `None` for a new note.
+    original: Option<Vec<String>>,
+}
+
+/// A custom command waiting for `run_app` to hand it the terminal.
+struct ForegroundCommand {
+    name: String,
+    argv: Vec<String>,
+    input: Option<String>,
+}
+
+/// State of the fuzzy line finder popup.
+#[derive(Clone)]
+struct LineFinder {
+    query: String,
+    /// Matching document lines, best first.
+    results: Vec<usize>,
+    /// Index into `results` of the highlighted entry.
+    selected: usize,
 }
 
 struct Document {
@@ -132,61 +202,135 @@ impl Document {
 }
 
 struct OverlayItem {
+    /// Identifies the overlay while others are added and removed. Assigned
+    /// by `App::insert_overlay` and `App::load_annotations`.
+    id: usize,
     after_line: usize,
     content: Vec<String>,
+    kind: OverlayKind,
+    /// Show only the first line followed by a count of hidden lines.
+    collapsed: bool,
 }
 
 enum DisplayLine<'a> {
-    Original(&'a str),
-    Overlay(&'a str),
+    /// A document line and its 0-based line number.
+    Original(&'a str, usize),
+    /// A line of overlay text; `index` is the position in `App::overlays` and
+    /// `hidden` counts the lines folded away behind a collapsed summary.
+    Overlay {
+        text: &'a str,
+        kind: OverlayKind,
+        index: usize,
+        hidden: usize,
+    },
 }
 
 impl<'a> DisplayLine<'a> {
     fn text(&self) -> &'a str {
         match self {
-            DisplayLine::Original(text) => text,
-            DisplayLine::Overlay(text) => text,
+            DisplayLine::Original(text, _) => text,
+            DisplayLine::Overlay { text, .. } => text,
+        }
+    }
+
+    /// Kind and hidden line count for overlay lines.
+    fn overlay_info(&self) -> Option<(OverlayKind, usize)> {
+        match self {
+            DisplayLine::Original(..) => None,
+            DisplayLine::Overlay { kind, hidden, .. } => Some((*kind, *hidden)),
         }
     }
 }
 
 impl Document {
-    fn compose<'a>(&'a self, overlays: &'a [OverlayItem]) -> Vec<DisplayLine<'a>> {
+    /// Interleaves the document with its overlays. With a filter only the
+    /// lines it keeps are shown, along with the overlays anchored to them.
+    fn compose<'a>(
+        &'a self,
+        overlays: &'a [OverlayItem],
+        filter: Option<&Filter>,
+    ) -> Vec<DisplayLine<'a>> {
         let mut result = Vec::new();
         let mut o_idx = 0;
         for (i, line) in self.lines.iter().enumerate() {
-            result.push(DisplayLine::Original(line));
+            let shown = filter.is_none_or(|f| f.keeps(line));
+            if shown {
+                result.push(DisplayLine::Original(line, i));
+            }
             while o_idx < overlays.len() && overlays[o_idx].after_line == i {
-                for text in &overlays[o_idx].content {
-                    result.push(DisplayLine::Overlay(text));
+                if shown {
+                    push_overlay(&mut result, overlays, o_idx);
                 }
                 o_idx += 1;
             }
         }
         while o_idx < overlays.len() {
-            for text in &overlays[o_idx].content {
-                result.push(DisplayLine::Overlay(text));
-            }
+            push_overlay(&mut result, overlays, o_idx);
             o_idx += 1;
         }
         result
     }
 }
 
+/// Appends the lines of `overlays[index]`, or a single summary line when it is
+/// collapsed.
+fn push_overlay<'a>(result: &mut Vec<DisplayLine<'a>>, overlays: &'a [OverlayItem], index: usize) {
+    let item = &overlays[index];
+    if item.collapsed && !item.content.is_empty() {
+        result.push(DisplayLine::Overlay {
+            text: &item.content[0],
+            kind: item.kind,
+            index,
+            hidden: item.content.len() - 1,
+        });
+        return;
+    }
+    for text in &item.content {
+        result.push(DisplayLine::Overlay {
+            text,
+            kind: item.kind,
+            index,
+            hidden: 0,
+        });
+    }
+}
+
 struct App {
     path: PathBuf,
     doc: Document,
     overlays: Vec<OverlayItem>,
+    next_overlay_id: usize,
     #[allow(dead_code)]
     commands: std::collections::HashMap<String, CommandSpec>,
     cursor_x: usize,
     cursor_y: usize,
     scroll: u16,
     mode: Mode,
-    search_query: Option<String>,
+    search_pattern: Option<Pattern>,
+    /// Direction of the last search; `n` continues in it and `N` reverses it.
+    search_backward: bool,
+    search_origin: Option<SearchOrigin>,
+    history: SearchHistory,
     search_hits: Vec<(usize, usize)>,
     current_hit: Option<usize>,
     selection_start: Option<(usize, usize)>,
+    /// Whether the selection was started with `V` and covers whole lines.
+    selection_linewise: bool,
+    /// Hides document lines that don't match, see `:filter`.
+    filter: Option<Filter>,
+    /// Patterns pinned with `:hl add`.
+    highlights: Vec<Highlight>,
+    options: Options,
+    /// Shown in the bottom line until the next key press.
+    message: Option<String>,
+    /// Custom commands still running, oldest first.
+    jobs: Vec<Job>,
+    next_job_id: usize,
+    job_tx: mpsc::Sender<JobDone>,
+    job_rx: mpsc::Receiver<JobDone>,
+    /// Set by commands marked `@`; run by the main loop, which owns the
+    /// terminal.
+    foreground: Option<ForegroundCommand>,
 }
 
 impl App {
@@ -195,24 +339,321 @@ impl App {
         for c in commands {
             map.insert(c.name.clone(), c);
         }
+        let (job_tx, job_rx) = mpsc::channel();
         Self {
             path,
             doc: Document::new(content),
             overlays: Vec::new(),
+            next_overlay_id: 0,
             commands: map,
             cursor_x: 0,
             cursor_y: 0,
             scroll: 0,
             mode: Mode::Normal,
-            search_query: None,
+            search_pattern: None,
+            search_backward: false,
+            search_origin: None,
+            history: SearchHistory::default(),
             search_hits: Vec::new(),
             current_hit: None,
             selection_start: None,
+            selection_linewise: false,
+            filter: None,
+            highlights: Vec::new(),
+            options: Options::default(),
+            message: None,
+            jobs: Vec::new(),
+            next_job_id: 0,
+            job_tx,
+            job_rx,
+            foreground: None,
+        }
+    }
+
+    fn display_lines(&self) -> Vec<DisplayLine<'_>> {
+        self.doc.compose(&self.overlays, self.filter.as_ref())
+    }
+
+    /// Maps a display line to the document line it belongs to. Overlay lines
+    /// map to the document line they are anchored after.
+    fn source_line(&self, y: usize) -> usize {
+        let last = self.doc.lines.len().saturating_sub(1);
+        match self.display_lines().get(y) {
+            Some(DisplayLine::Original(_, line)) => *line,
+            Some(DisplayLine::Overlay { index, .. }) => self.overlays[*index].after_line.min(last),
+            None => last,
+        }
+    }
+
+    /// The ends of the visual selection in display order, or the cursor
+    /// position twice when nothing is selected.
+    fn selection_bounds(&self) -> ((usize, usize), (usize, usize)) {
+        let cursor = (self.cursor_y, self.cursor_x);
+        match self.selection_start {
+            Some(start) if start <= cursor => (start, cursor),
+            Some(start) => (cursor, start),
+            None => (cursor, cursor),
+        }
+    }
+
+    /// The document text covered by the visual selection as it is
+    /// highlighted. Overlay lines are left out.
+    fn selected_text(&self) -> String {
+        if self.selection_start.is_none() {
+            return String::new();
+        }
+        let ((sy, sx), (ey, ex)) = self.selection_bounds();
+        let lines = self.display_lines();
+        let mut selected = Vec::new();
+        for (y, line) in lines.iter().enumerate().take(ey + 1).skip(sy) {
+            let DisplayLine::Original(text, _) = line else {
+                continue;
+            };
+            if self.selection_linewise {
+                selected.push(*text);
+                continue;
+            }
+            let start = if y == sy { sx.min(text.len()) } else { 0 };
+            let end = if y == ey {
+                ex.min(text.len())
+            } else {
+                text.len()
+            };
+            selected.push(text.get(start..end.max(start)).unwrap_or_default());
+        }
+        selected.join("\n")
+    }
+
+    /// Text piped to commands that read stdin: the selection, or the whole
+    /// document when nothing is selected.
+    fn piped_input(&self) -> String {
+        let mut input = match self.selection_start {
+            Some(_) => self.selected_text(),
+            None => self.doc.lines.join("\n"),
+        };
+        input.push('\n');
+        input
+    }
+
+    /// The value of `{name}` in a custom command template, or `None` when
+    /// `name` isn't a placeholder.
+    fn placeholder(&self, name: &str, args: &str) -> Option<String> {
+        let ((sy, sx), (ey, ex)) = self.selection_bounds();
+        Some(match name {
+            "line" => (self.source_line(self.cursor_y) + 1).to_string(),
+            "col" => (self.cursor_x + 1).to_string(),
+            "args" => args.to_string(),
+            "start_line" => (self.source_line(sy) + 1).to_string(),
+            "start_col" => (sx + 1).to_string(),
+            "end_line" => (self.source_line(ey) + 1).to_string(),
+            "end_col" => (ex + 1).to_string(),
+            "file" => self.path.display().to_string(),
+            "dir" => match self.path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir.display().to_string(),
+                _ => ".".to_string(),
+            },
+            "word" => self
+                .word_under_cursor()
+                .map(|(_, word)| word)
+                .unwrap_or_default(),
+            "selection" => self.selected_text(),
+            "query" => self
+                .search_pattern
+                .as_ref()
+                .map(|p| p.query.clone())
+                .unwrap_or_default(),
+            _ => return None,
+        })
+    }
+
+    fn insert_overlay(&mut self, mut item: OverlayItem) -> usize {
+        item.id = self.next_overlay_id;
+        self.next_overlay_id += 1;
+        let idx = self
+            .overlays
+            .partition_point(|o| o.after_line <= item.after_line);
+        self.overlays.insert(idx, item);
+        idx
+    }
+
+    /// Starts writing a note. On an overlay line the overlay is edited in
+    /// place; otherwise a new note is added after the cursor line, or after
+    /// the end of the visual selection, as long as that line is shown.
+    fn start_note(&mut self) {
+        let draft = match self.display_lines().get(self.cursor_y) {
+            Some(DisplayLine::Overlay { index, .. }) => Some((
+                *index,
+                NoteDraft {
+                    id: self.overlays[*index].id,
+                    original: Some(self.overlays[*index].content.clone()),
+                },
+            )),
+            _ => None,
+        };
+        let (index, draft) = draft.unwrap_or_else(|| {
+            let end = match self.selection_start {
+                Some((y, _)) => y.max(self.cursor_y),
+                None => self.cursor_y,
+            };
+            let index = self.insert_overlay(OverlayItem {
+                id: 0,
+                after_line: self.source_line(end),
+                content: vec![String::new()],
+                kind: OverlayKind::Note,
+                collapsed: false,
+            });
+            let draft = NoteDraft {
+                id: self.overlays[index].id,
+                original: None,
+            };
+            (index, draft)
+        });
+        // e.g. nothing is shown under the filter, so the note would be hidden
+        let shown = self
+            .display_lines()
+            .iter()
+            .any(|l| matches!(l, DisplayLine::Overlay { index: i, .. } if *i == index));
+        if !shown && draft.original.is_none() {
+            self.overlays.remove(index);
+            self.message = Some("Cannot add a note to a line hidden by the filter".to_string());
+            return;
+        }
+        self.overlays[index].collapsed = false;
+        self.selection_start = None;
+        self.mode = Mode::Note(draft);
+        self.note_cursor();
+    }
+
+    /// Position in `overlays` of the note being edited, which moves as other
+    /// overlays are added or removed, e.g. by a reload.
+    fn note_index(&self) -> Option<usize> {
+        match &self.mode {
+            Mode::Note(draft) => self.overlays.iter().position(|o| o.id == draft.id),
+            _ => None,
+        }
+    }
+
+    fn note_lines(&mut self) -> Option<&mut Vec<String>> {
+        let index = self.note_index()?;
+        Some(&mut self.overlays[index].content)
+    }
+
+    /// Puts the cursor at the end of the note being edited.
+    fn note_cursor(&mut self) {
+        let Some(target) = self.note_index() else {
+            return;
+        };
+        let last = self
+            .display_lines()
+            .iter()
+            .enumerate()
+            .filter(|(_, l)| matches!(l, DisplayLine::Overlay { index, .. } if *index == target))
+            .map(|(i, l)| (i, l.text().len()))
+            .next_back();
+        if let Some((y, x)) = last {
+            self.cursor_y = y;
+            self.cursor_x = x;
+        }
+    }
+
+    fn note_char(&mut self, ch: char) {
+        if let Some(line) = self.note_lines().and_then(|lines| lines.last_mut()) {
+            line.push(ch);
+        }
+        self.note_cursor();
+    }
+
+    fn note_newline(&mut self) {
+        if let Some(lines) = self.note_lines() {
+            lines.push(String::new());
+        }
+        self.note_cursor();
+    }
+
+    fn note_backspace(&mut self) {
+        if let Some(lines) = self.note_lines() {
+            if lines.last().is_some_and(|l| l.is_empty()) && lines.len() > 1 {
+                lines.pop();
+            } else if let Some(line) = lines.last_mut() {
+                line.pop();
+            }
+        }
+        self.note_cursor();
+    }
+
+    /// Keeps the note, removing it if it was left empty.
+    fn finish_note(&mut self) {
+        if let Some(index) = self.note_index() {
+            let lines = &mut self.overlays[index].content;
+            while lines.len() > 1 && lines.last().is_some_and(|l| l.is_empty()) {
+                lines.pop();
+            }
+            if lines.iter().all(|l| l.is_empty()) {
+                self.overlays.remove(index);
+            }
+        }
+        self.mode = Mode::Normal;
+        self.clamp_cursor();
+    }
+
+    /// Discards the edit, restoring the original note or dropping a new one.
+    fn cancel_note(&mut self) {
+        let index = self.note_index();
+        if let Mode::Note(draft) = std::mem::replace(&mut self.mode, Mode::Normal)
+            && let Some(index) = index
+        {
+            match draft.original {
+                Some(original) => self.overlays[index].content = original,
+                None => {
+                    self.overlays.remove(index);
+                }
+            }
+        }
+        self.clamp_cursor();
+    }
+
+    fn delete_overlay(&mut self, height: u16) {
+        if let Some(DisplayLine::Overlay { index, .. }) = self.display_lines().get(self.cursor_y) {
+            let index = *index;
+            self.overlays.remove(index);
+            self.refresh_search_hits();
+            self.clamp_cursor();
+            self.ensure_visible(height);
         }
     }
 
-    fn display_lines(&self) -> Vec<DisplayLine> {
-        self.doc.compose(&self.overlays)
+    fn clamp_cursor(&mut self) {
+        self.cursor_y = self
+            .cursor_y
+            .min(self.display_lines().len().saturating_sub(1));
+        self.cursor_x = self.cursor_x.min(self.line_len(self.cursor_y));
+    }
+
+    /// Replaces the document contents, keeping overlays attached to the lines
+    /// they were anchored after. An edited anchor line keeps its overlays;
+    /// overlays whose anchor line was deleted are dropped.
+    fn reload(&mut self, content: String) {
+        let old_lines = std::mem::take(&mut self.doc.lines);
+        self.doc.reload(content);
+        let mapping = overlay::line_mapping(&old_lines, &self.doc.lines);
+        self.overlays
+            .retain_mut(|o| match mapping.get(o.after_line) {
+                Some(Some(new_line)) => {
+                    o.after_line = *new_line;
+                    true
+                }
+                Some(None) => false,
+                None => true,
+            });
+        // output of a running job goes after the nearest line that survived
+        for job in &mut self.jobs {
+            if let Some(line) = job.capture_after.as_mut() {
+                let before = &mapping[..mapping.len().min(*line + 1)];
+                *line = before.iter().rev().find_map(|l| *l).unwrap_or(0);
+            }
+        }
+        self.refresh_search_hits();
+        self.update_finder();
     }
 
     fn line_len(&self, line: usize) -> usize {
@@ -270,23 +711,39 @@ impl App {
         }
     }
 
-    fn char_at(&self, y: usize, x: usize) -> Option<u8> {
+    /// Lines visited by word and paragraph motions as `(display index, text)`.
+    /// Overlay lines are skipped unless `overlaymotion` is set, except for the
+    /// line under the cursor so motions can start from inside an overlay.
+    fn motion_lines(&self) -> Vec<(usize, &str)> {
         self.display_lines()
+            .into_iter()
+            .enumerate()
+            .filter(|(i, l)| {
+                self.options.overlay_motion
+                    || *i == self.cursor_y
+                    || matches!(l, DisplayLine::Original(..))
+            })
+            .map(|(i, l)| (i, l.text()))
+            .collect()
+    }
+
+    fn char_at(&self, y: usize, x: usize) -> Option<u8> {
+        self.motion_lines()
             .get(y)
-            .and_then(|l| l.text().as_bytes().get(x))
+            .and_then(|l| l.1.as_bytes().get(x))
             .copied()
     }
 
     fn char_before(&self, y: usize, x: usize) -> Option<u8> {
-        let lines = self.display_lines();
+        let lines = self.motion_lines();
         if x > 0 {
             return lines
                 .get(y)
-                .and_then(|l| l.text().as_bytes().get(x - 1))
+                .and_then(|l| l.1.as_bytes().get(x - 1))
                 .copied();
         }
         if y > 0 {
-            return lines.get(y - 1)?.text().as_bytes().last().copied();
+            return lines.get(y - 1)?.1.as_bytes().last().copied();
         }
         None
     }
@@ -295,9 +752,9 @@ impl App {
     where
         F: Fn(u8) -> bool,
     {
-        let lines = self.display_lines();
+        let lines = self.motion_lines();
         while *y < lines.len() {
-            let bytes = lines[*y].text().as_bytes();
+            let bytes = lines[*y].1.as_bytes();
             while *x < bytes.len() && pred(bytes[*x]) {
                 *x += 1;
             }
@@ -316,19 +773,19 @@ impl App {
     where
         F: Fn(u8) -> bool,
     {
-        let lines = self.display_lines();
+        let lines = self.motion_lines();
         loop {
             if *y == 0 && *x == 0 {
                 return;
             }
             if *x == 0 {
                 *y -= 1;
-                *x = lines[*y].text().len();
+                *x = lines[*y].1.len();
                 if *x == 0 {
                     continue;
                 }
             }
-            let bytes = lines[*y].text().as_bytes();
+            let bytes = lines[*y].1.as_bytes();
             while *x > 0 && pred(bytes[*x - 1]) {
                 *x -= 1;
             }
@@ -336,8 +793,27 @@ impl App {
         }
     }
 
+    /// Index of the cursor line within `motion_lines()`.
+    fn motion_cursor(&self) -> Option<usize> {
+        self.motion_lines()
+            .iter()
+            .position(|(i, _)| *i == self.cursor_y)
+    }
+
+    /// Moves the cursor to position `x` on the `y`th motion line.
+    fn set_motion_cursor(&mut self, y: usize, x: usize) {
+        let lines = self.motion_lines();
+        if let Some(&(display_y, text)) = lines.get(y.min(lines.len().saturating_sub(1))) {
+            let len = text.len();
+            self.cursor_y = display_y;
+            self.cursor_x = x.min(len);
+        }
+    }
+
     fn move_word_forward(&mut self) {
-        let mut y = self.cursor_y;
+        let Some(mut y) = self.motion_cursor() else {
+            return;
+        };
         let mut x = self.cursor_x;
 
         if let Some(c) = self.char_at(y, x) {
@@ -354,9 +830,7 @@ impl App {
 
         self.skip_forward(&mut y, &mut x, |b| b.is_ascii_whitespace());
 
-        let lines_len = self.display_lines().len();
-        self.cursor_y = y.min(lines_len.saturating_sub(1));
-        self.cursor_x = x.min(self.line_len(self.cursor_y));
+        self.set_motion_cursor(y, x);
     }
 
     fn move_word_backward(&mut self) {
@@ -364,7 +838,9 @@ impl App {
             return;
         }
 
-        let mut y = self.cursor_y;
+        let Some(mut y) = self.motion_cursor() else {
+            return;
+        };
         let mut x = self.cursor_x;
 
         self.skip_backward(&mut y, &mut x, |b| b.is_ascii_whitespace());
@@ -379,36 +855,33 @@ impl App {
             }
         }
 
-        self.cursor_y = y;
-        self.cursor_x = x;
+        self.set_motion_cursor(y, x);
     }
 
     fn move_paragraph_down(&mut self) {
-        let lines = self.display_lines();
-        for (i, line) in lines.iter().enumerate().skip(self.cursor_y + 1) {
-            if line.text().trim().is_empty() {
-                self.cursor_y = i;
-                self.cursor_x = 0;
-                return;
-            }
+        let lines = self.motion_lines();
+        let target = lines
+            .iter()
+            .find(|(i, text)| *i > self.cursor_y && text.trim().is_empty())
+            .or(lines.last())
+            .map(|(i, _)| *i);
+        if let Some(y) = target {
+            self.cursor_y = y;
+            self.cursor_x = 0;
         }
-        self.cursor_y = lines.len().saturating_sub(1);
-        self.cursor_x = 0;
     }
 
     fn move_paragraph_up(&mut self) {
         if self.cursor_y == 0 {
             return;
         }
-        let lines = self.display_lines();
-        for (i, line) in lines.iter().enumerate().take(self.cursor_y).rev() {
-            if line.text().trim().is_empty() {
-                self.cursor_y = i;
-                self.cursor_x = 0;
-                return;
-            }
-        }
-        self.cursor_y = 0;
+        let lines = self.motion_lines();
+        let target = lines
+            .iter()
+            .rev()
+            .find(|(i, text)| *i < self.cursor_y && text.trim().is_empty())
+            .map(|(i, _)| *i);
+        self.cursor_y = target.unwrap_or(0);
         self.cursor_x = 0;
     }
 
@@ -433,7 +906,8 @@ impl App {
     }
 
     fn cursor_middle(&mut self, height: u16) {
-        let mid = (self.scroll + height / 2).min(self.display_lines().len() as u16 - 1);
+        let last = self.display_lines().len().saturating_sub(1) as u16;
+        let mid = (self.scroll + height / 2).min(last);
         self.cursor_y = mid as usize;
         let len = self.line_len(self.cursor_y);
         if self.cursor_x > len {
@@ -442,7 +916,8 @@ impl App {
     }
 
     fn cursor_bottom(&mut self, height: u16) {
-        let bottom = (self.scroll + height - 1).min(self.display_lines().len() as u16 - 1);
+        let last = self.display_lines().len().saturating_sub(1) as u16;
+        let bottom = (self.scroll + height).saturating_sub(1).min(last);
         self.cursor_y = bottom as usize;
         let len = self.line_len(self.cursor_y);
         if self.cursor_x > len {
@@ -463,133 +938,670 @@ impl App {
         }
     }
 
-    fn set_search_query(&mut self, query: String) {
-        if query.is_empty() {
-            self.search_query = None;
-            self.search_hits.clear();
-            self.current_hit = None;
-            return;
+    /// Shows only lines matching `query` (or, with `inverse`, not matching
+    /// it). An empty query removes the filter.
+    fn set_filter(&mut self, query: &str, inverse: bool, height: u16) {
+        let filter = match query {
+            q if search::is_empty_query(q) => None,
+            q => match Pattern::new(q, &self.options) {
+                Ok(pattern) => Some(Filter { pattern, inverse }),
+                Err(err) => {
+                    self.message = Some(err);
+                    return;
+                }
+            },
+        };
+        self.relayout(height, |app| app.filter = filter);
+        if let Some(filter) = &self.filter {
+            let shown = self.doc.lines.iter().filter(|l| filter.keeps(l)).count();
+            self.message = Some(format!("{} of {} lines", shown, self.doc.lines.len()));
         }
-        self.search_query = Some(query.clone());
-        self.search_hits.clear();
-        let lines: Vec<String> = self
-            .display_lines()
-            .into_iter()
-            .map(|l| l.text().to_owned())
-            .collect();
-        for (y, line) in lines.iter().enumerate() {
-            let mut start = 0;
-            while let Some(pos) = line[start..].find(&query) {
-                self.search_hits.push((y, start + pos));
-                start += pos + query.len();
+    }
+
+    /// Pins `spec` (`PATTERN COLOR`), replacing an existing pin of the same
+    /// pattern.
+    fn add_highlight(&mut self, spec: &str) {
+        match Highlight::parse(spec, &self.options) {
+            Ok(highlight) => {
+                self.highlights
+                    .retain(|h| h.pattern.query != highlight.pattern.query);
+                self.highlights.push(highlight);
             }
+            Err(err) => self.message = Some(err),
         }
-        self.current_hit = if self.search_hits.is_empty() {
-            None
-        } else {
-            Some(0)
-        };
-        if let Some(idx) = self.current_hit {
-            let (y, x) = self.search_hits[idx];
-            self.cursor_y = y;
-            self.cursor_x = x;
+    }
+
+    fn remove_highlight(&mut self, query: &str) {
+        let before = self.highlights.len();
+        self.highlights.retain(|h| h.pattern.query != query);
+        if self.highlights.len() == before {
+            self.message = Some(format!("No highlight for {query}"));
         }
     }
 
-    fn clear_search(&mut self) {
-        self.search_query = None;
-        self.search_hits.clear();
-        self.current_hit = None;
+    fn list_highlights(&mut self) {
+        self.message = Some(if self.highlights.is_empty() {
+            "No highlights".to_string()
+        } else {
+            self.highlights
+                .iter()
+                .map(|h| format!("{} ({})", h.pattern.query, h.color))
+                .collect::<Vec<_>>()
+                .join(", ")
+        });
     }
 
-    fn next_hit(&mut self, height: u16) {
-        if self.search_hits.is_empty() {
+    fn save_annotations(&mut self) {
+        self.message = Some(
+            match annotations::save(&self.path, &self.doc.lines, &self.overlays) {
+                Ok(target) => format!(
+                    "Saved {} annotations to {}",
+                    self.overlays.len(),
+                    target.display()
+                ),
+                Err(err) => format!("Cannot save annotations: {err}"),
+            },
+        );
+    }
+
+    fn export_review(&mut self, target: &str) {
+        if target.is_empty() {
+            self.message = Some("Usage: export-review <path>".to_string());
             return;
         }
-        let next = match self.current_hit {
-            Some(i) => (i + 1) % self.search_hits.len(),
-            None => 0,
-        };
-        self.current_hit = Some(next);
-        let (y, x) = self.search_hits[next];
-        self.cursor_y = y;
-        self.cursor_x = x;
-        self.ensure_visible(height);
+        let target = PathBuf::from(target);
+        let format = export::ReviewFormat::for_path(&target);
+        let report = export::render(format, &self.path, &self.doc.lines, &self.overlays);
+        self.message = Some(match std::fs::write(&target, report) {
+            Ok(()) => format!("Exported review to {}", target.display()),
+            Err(err) => format!("Cannot export review: {err}"),
+        });
     }
 
-    fn prev_hit(&mut self, height: u16) {
-        if self.search_hits.is_empty() {
-            return;
+    /// Replaces the overlays with those saved in the sidecar file. A missing
+    /// sidecar is not an error.
+    fn load_annotations(&mut self) {
+        match annotations::load(&self.path, &self.doc.lines) {
+            Ok((items, dropped)) => {
+                let mut message = format!("Loaded {} annotations", items.len());
+                if dropped > 0 {
+                    message.push_str(&format!(", {dropped} dropped"));
+                }
+                self.overlays = items;
+                for overlay in &mut self.overlays {
+                    overlay.id = self.next_overlay_id;
+                    self.next_overlay_id += 1;
+                }
+                self.refresh_search_hits();
+                self.message = Some(message);
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => self.message = Some(format!("Cannot load annotations: {err}")),
         }
-        let prev = match self.current_hit {
-            Some(0) | None => self.search_hits.len() - 1,
-            Some(i) => i - 1,
+    }
+
+    /// Applies `f`, which changes which lines are displayed, keeping the
+    /// cursor on the same overlay or document line (or the next one shown).
+    fn relayout<F>(&mut self, height: u16, f: F)
+    where
+        F: FnOnce(&mut Self),
+    {
+        let overlay = match self.display_lines().get(self.cursor_y) {
+            Some(DisplayLine::Overlay { index, .. }) => Some(*index),
+            _ => None,
         };
-        self.current_hit = Some(prev);
-        let (y, x) = self.search_hits[prev];
-        self.cursor_y = y;
-        self.cursor_x = x;
+        let source = self.source_line(self.cursor_y);
+        f(self);
+
+        let lines = self.display_lines();
+        let y = match overlay {
+            Some(target) => lines
+                .iter()
+                .position(|l| matches!(l, DisplayLine::Overlay { index, .. } if *index == target)),
+            None => None,
+        };
+        let y = y.or_else(|| {
+            lines
+                .iter()
+                .position(|l| matches!(l, DisplayLine::Original(_, line) if *line >= source))
+        });
+        self.cursor_y = y.unwrap_or(0).min(lines.len().saturating_sub(1));
+        self.cursor_x = self.cursor_x.min(self.line_len(self.cursor_y));
+        self.refresh_search_hits();
         self.ensure_visible(height);
     }
-}
 
-#[derive(Parser)]
-struct Cli {
-    /// Print the file without launching the TUI
-    #[arg(long)]
-    headless: bool,
+    fn toggle_overlay(&mut self, height: u16) {
+        if let Some(DisplayLine::Overlay { index, .. }) = self.display_lines().get(self.cursor_y) {
+            let index = *index;
+            self.relayout(height, |app| {
+                app.overlays[index].collapsed = !app.overlays[index].collapsed;
+            });
+        }
+    }
 
-    /// Custom command definitions in the form <name>:<template>
-    #[arg(long = "command", action = clap::ArgAction::Append)]
-    commands: Vec<CommandSpec>,
+    fn set_all_overlays_collapsed(&mut self, collapsed: bool, height: u16) {
+        self.relayout(height, |app| {
+            for o in &mut app.overlays {
+                o.collapsed = collapsed;
+            }
+        });
+    }
 
-    /// Path to the file to view
-    path: PathBuf,
-}
+    /// Display indices of the first line of each overlay block.
+    fn overlay_starts(&self) -> Vec<usize> {
+        let mut starts = Vec::new();
+        let mut last = None;
+        for (i, line) in self.display_lines().iter().enumerate() {
+            if let DisplayLine::Overlay { index, .. } = line {
+                if last != Some(*index) {
+                    starts.push(i);
+                }
+                last = Some(*index);
+            } else {
+                last = None;
+            }
+        }
+        starts
+    }
 
-fn main() -> Result<()> {
-    let args = Cli::parse();
-    let mut file = File::open(&args.path)?;
-    let mut content = String::new();
-    file.read_to_string(&mut content)?;
+    fn next_overlay(&mut self, height: u16) {
+        if let Some(&y) = self.overlay_starts().iter().find(|&&y| y > self.cursor_y) {
+            self.cursor_y = y;
+            self.cursor_x = 0;
+            self.ensure_visible(height);
+        }
+    }
 
-    if args.headless {
-        println!("{}", content);
-        return Ok(());
+    fn prev_overlay(&mut self, height: u16) {
+        if let Some(&y) = self
+            .overlay_starts()
+            .iter()
+            .rev()
+            .find(|&&y| y < self.cursor_y)
+        {
+            self.cursor_y = y;
+            self.cursor_x = 0;
+            self.ensure_visible(height);
+        }
     }
 
-    // setup terminal
-    enable_raw_mode()?;
-    let mut stdout = io::stdout();
-    execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
-    let backend = CrosstermBackend::new(stdout);
-    let mut terminal = Terminal::new(backend)?;
+    /// Starts a custom command in the background; its output, if captured,
+    /// goes after `capture_after` once it finishes.
+    fn start_job(
+        &mut self,
+        name: &str,
+        argv: &[String],
+        input: Option<String>,
+        capture_after: Option<usize>,
+    ) {
+        let id = self.next_job_id;
+        self.next_job_id += 1;
+        match Job::spawn(id, name, argv, input, capture_after, self.job_tx.clone()) {
+            Ok(job) => self.jobs.push(job),
+            Err(err) => self.message = Some(format!("Cannot run {name}: {err}")),
+        }
+    }
 
-    let res = run_app(&mut terminal, args.path.clone(), content, args.commands);
+    /// Handles a finished job: captured output becomes overlays and failures
+    /// are reported. Cancelled jobs are already gone and are ignored.
+    fn finish_job(&mut self, done: JobDone) {
+        let Some(pos) = self.jobs.iter().position(|j| j.id == done.id) else {
+            return;
+        };
+        let job = self.jobs.remove(pos);
+        match done.status {
+            Ok(status) if status.success() => {}
+            Ok(status) => {
+                let reason = done.stderr.lines().next().unwrap_or_default();
+                self.message = Some(match status.code() {
+                    Some(code) => format!("{} exited with {code}: {reason}", job.name),
+                    None => format!("{} was killed", job.name),
+                });
+            }
+            Err(err) => self.message = Some(format!("Cannot run {}: {err}", job.name)),
+        }
+        let Some(after_line) = job.capture_after else {
+            return;
+        };
+        if let Some(items) = overlay::parse_records(&done.stdout) {
+            for item in items {
+                self.insert_overlay(item);
+            }
+        } else {
+            let content: Vec<String> = done.stdout.lines().map(|l| l.to_string()).collect();
+            if !content.is_empty() {
+                self.insert_overlay(OverlayItem {
+                    id: 0,
+                    after_line,
+                    content,
+                    kind: OverlayKind::default(),
+                    collapsed: false,
+                });
+            }
+        }
+    }
 
-    // restore terminal
-    disable_raw_mode()?;
-    execute!(
-        terminal.backend_mut(),
-        LeaveAlternateScreen,
-        DisableMouseCapture
-    )?;
-    terminal.show_cursor()?;
+    /// Kills the most recently started job.
+    fn cancel_job(&mut self) {
+        if let Some(job) = self.jobs.pop() {
+            job.cancel();
+            self.message = Some(format!("Cancelled {}", job.name));
+        }
+    }
 
-    if let Err(err) = res {
-        println!("{:?}", err)
+    /// Spinner and name of the running job, or the number running.
+    fn job_status(&self) -> Option<String> {
+        let last = self.jobs.last()?;
+        Some(match self.jobs.len() {
+            1 => format!("{} {}", last.spinner(), last.name),
+            n => format!("{} {n} jobs", last.spinner()),
+        })
     }
 
-    Ok(())
-}
+    /// Status text describing the overlays, e.g. `overlay 2/5` when the cursor
+    /// is on one.
+    fn overlay_status(&self) -> Option<String> {
+        let total = self.overlays.len();
+        if total == 0 {
+            return None;
+        }
+        match self.display_lines().get(self.cursor_y) {
+            Some(DisplayLine::Overlay { index, .. }) => {
+                Some(format!("overlay {}/{}", index + 1, total))
+            }
+            _ if total == 1 => Some("1 overlay".to_string()),
+            _ => Some(format!("{total} overlays")),
+        }
+    }
 
-fn run_app<B: Backend>(
-    terminal: &mut Terminal<B>,
-    path: PathBuf,
-    content: String,
-    commands: Vec<CommandSpec>,
+    fn find_hits(&self, pattern: &Pattern) -> Vec<(usize, usize)> {
+        let mut hits = Vec::new();
+        for (y, line) in self.display_lines().iter().enumerate() {
+            if !self.options.overlay_search && !matches!(line, DisplayLine::Original(..)) {
+                continue;
+            }
+            hits.extend(
+                pattern
+                    .matches(line.text())
+                    .into_iter()
+                    .map(|(x, _)| (y, x)),
+            );
+        }
+        hits
+    }
+
+    /// Index of the first hit after (or, searching backward, before) the given
+    /// position, and whether the search wrapped around the end of the file.
+    fn hit_from(&self, y: usize, x: usize, backward: bool) -> Option<(usize, bool)> {
+        let last = self.search_hits.len().checked_sub(1)?;
+        Some(if backward {
+            match self.search_hits.iter().rposition(|&hit| hit < (y, x)) {
+                Some(i) => (i, false),
+                None => (last, true),
+            }
+        } else {
+            match self.search_hits.iter().position(|&hit| hit > (y, x)) {
+                Some(i) => (i, false),
+                None => (0, true),
+            }
+        })
+    }
+
+    /// Moves to the next hit in the given direction, announcing wrap-around
+    /// and failed searches. With `show_count` the message ends with the
+    /// position of the hit, e.g. `[3/17]`.
+    fn jump_from_cursor(&mut self, backward: bool, show_count: bool) {
+        let Some(pattern) = &self.search_pattern else {
+            self.message = Some("No previous search pattern".to_string());
+            return;
+        };
+        let Some((idx, wrapped)) = self.hit_from(self.cursor_y, self.cursor_x, backward) else {
+            self.current_hit = None;
+            self.message = Some(format!("Pattern not found: {}", pattern.query));
+            return;
+        };
+        let prompt = if self.search_backward { '?' } else { '/' };
+        let mut message = match (wrapped, backward) {
+            (true, true) => "search hit TOP, continuing at BOTTOM".to_string(),
+            (true, false) => "search hit BOTTOM, continuing at TOP".to_string(),
+            (false, _) => format!("{}{}", prompt, pattern.query),
+        };
+        if show_count {
+            message.push_str(&format!(" [{}/{}]", idx + 1, self.search_hits.len()));
+        }
+        if wrapped || show_count {
+            self.message = Some(message);
+        }
+        self.current_hit = Some(idx);
+        (self.cursor_y, self.cursor_x) = self.search_hits[idx];
+    }
+
+    /// Makes `pattern` the current search and moves to its first hit from the
+    /// cursor in the search direction.
+    fn apply_search(&mut self, pattern: Option<Pattern>) {
+        self.search_hits = match &pattern {
+            Some(pattern) => self.find_hits(pattern),
+            None => Vec::new(),
+        };
+        self.search_pattern = pattern;
+        self.current_hit = None;
+        if self.search_pattern.is_some() {
+            self.jump_from_cursor(self.search_backward, false);
+        }
+    }
+
+    /// Searches for `query`, moving to the first hit after the cursor. An
+    /// invalid pattern leaves the previous search in place and reports the
+    /// error.
+    fn set_search_query(&mut self, query: String) {
+        if search::is_empty_query(&query) {
+            self.apply_search(None);
+            return;
+        }
+        match Pattern::new(&query, &self.options) {
+            Ok(pattern) => self.apply_search(Some(pattern)),
+            Err(err) => self.message = Some(err),
+        }
+    }
+
+    fn begin_search(&mut self, backward: bool) {
+        self.search_origin = Some(SearchOrigin {
+            backward: self.search_backward,
+            cursor: (self.cursor_y, self.cursor_x),
+            scroll: self.scroll,
+            pattern: self.search_pattern.take(),
+            hits: std::mem::take(&mut self.search_hits),
+            current_hit: self.current_hit.take(),
+        });
+        self.search_backward = backward;
+        self.history.reset();
+        self.mode = Mode::Search(String::new());
+    }
+
+    /// Replaces the query being typed with an older or newer history entry
+    /// that starts with the text typed so far.
+    fn recall_search(&mut self, older: bool, height: u16) {
+        let Mode::Search(query) = &self.mode else {
+            return;
+        };
+        let recalled = if older {
+            self.history.older(query)
+        } else {
+            self.history.newer()
+        };
+        if let Some(recalled) = recalled {
+            self.mode = Mode::Search(recalled);
+            self.preview_search(height);
+        }
+    }
+
+    /// Highlights and jumps to the query being typed, starting from where the
+    /// search began. Incomplete patterns show no hits.
+    fn preview_search(&mut self, height: u16) {
+        let (Some(origin), Mode::Search(query)) = (&self.search_origin, &self.mode) else {
+            return;
+        };
+        (self.cursor_y, self.cursor_x) = origin.cursor;
+        self.scroll = origin.scroll;
+        let pattern = match query.as_str() {
+            q if search::is_empty_query(q) => None,
+            q => Pattern::new(q, &self.options).ok(),
+        };
+        self.apply_search(pattern);
+        self.message = None;
+        self.ensure_visible(height);
+    }
+
+    /// Puts back the cursor, scroll position and search from before search
+    /// mode was entered.
+    fn cancel_search(&mut self) {
+        if let Some(origin) = self.search_origin.take() {
+            self.search_backward = origin.backward;
+            (self.cursor_y, self.cursor_x) = origin.cursor;
+            self.scroll = origin.scroll;
+            self.search_pattern = origin.pattern;
+            self.search_hits = origin.hits;
+            self.current_hit = origin.current_hit;
+        }
+    }
+
+    fn submit_search(&mut self) {
+        let query = match &self.mode {
+            Mode::Search(q) => q.clone(),
+            _ => return,
+        };
+        let backward = self.search_backward;
+        self.cancel_search();
+        self.search_backward = backward;
+        // history is a convenience; failing to save it shouldn't stop the search
+        let _ = self.history.push(&query);
+        self.set_search_query(query);
+    }
+
+    /// The keyword at or after the cursor on its line, with its start.
+    fn word_under_cursor(&self) -> Option<(usize, String)> {
+        let lines = self.display_lines();
+        let bytes = lines.get(self.cursor_y)?.text().as_bytes();
+        let mut start = self.cursor_x;
+        while start < bytes.len() && !is_keyword(bytes[start]) {
+            start += 1;
+        }
+        if start >= bytes.len() {
+            return None;
+        }
+        while start > 0 && is_keyword(bytes[start - 1]) {
+            start -= 1;
+        }
+        let mut end = start;
+        while end < bytes.len() && is_keyword(bytes[end]) {
+            end += 1;
+        }
+        Some((
+            start,
+            String::from_utf8_lossy(&bytes[start..end]).into_owned(),
+        ))
+    }
+
+    /// Searches for the whole keyword under the cursor, like `*` and `#` in
+    /// Vim. Only `ignorecase` applies; `smartcase` is ignored.
+    fn search_word(&mut self, backward: bool, height: u16) {
+        let Some((start, word)) = self.word_under_cursor() else {
+            self.message = Some("No string under cursor".to_string());
+            return;
+        };
+        let mut query = format!("\\<{word}\\>");
+        if self.options.ignore_case {
+            query.push_str("\\c");
+        }
+        let pattern = match Pattern::new(&query, &self.options) {
+            Ok(pattern) => pattern,
+            Err(err) => {
+                self.message = Some(err);
+                return;
+            }
+        };
+        let _ = self.history.push(&query);
+        self.search_backward = backward;
+        self.cursor_x = start;
+        self.search_hits = self.find_hits(&pattern);
+        self.search_pattern = Some(pattern);
+        self.jump_from_cursor(backward, true);
+        self.ensure_visible(height);
+    }
+
+    fn open_finder(&mut self, query: &str) {
+        self.mode = Mode::Finder(LineFinder {
+            query: query.to_string(),
+            results: Vec::new(),
+            selected: 0,
+        });
+        self.update_finder();
+    }
+
+    /// Re-ranks the document lines against the finder query. Lines hidden by
+    /// the filter are left out.
+    fn update_finder(&mut self) {
+        let Mode::Finder(finder) = &mut self.mode else {
+            return;
+        };
+        let lines = self.doc.lines.iter().map(String::as_str).enumerate();
+        finder.results = match &self.filter {
+            Some(filter) => finder::rank(&finder.query, lines.filter(|(_, l)| filter.keeps(l))),
+            None => finder::rank(&finder.query, lines),
+        };
+        finder.selected = 0;
+    }
+
+    fn move_finder_selection(&mut self, down: bool) {
+        if let Mode::Finder(finder) = &mut self.mode {
+            finder.selected = if down {
+                (finder.selected + 1).min(finder.results.len().saturating_sub(1))
+            } else {
+                finder.selected.saturating_sub(1)
+            };
+        }
+    }
+
+    /// Closes the finder and moves to the selected line.
+    fn submit_finder(&mut self, height: u16) {
+        let Mode::Finder(finder) = std::mem::replace(&mut self.mode, Mode::Normal) else {
+            return;
+        };
+        let Some(&target) = finder.results.get(finder.selected) else {
+            return;
+        };
+        let found = self
+            .display_lines()
+            .iter()
+            .position(|l| matches!(l, DisplayLine::Original(_, n) if *n == target));
+        if let Some(y) = found {
+            self.cursor_y = y;
+            self.cursor_x = 0;
+            self.ensure_visible(height);
+        }
+    }
+
+    /// Recomputes hits for the current query without moving the cursor.
+    /// Patterns are recompiled too so option changes take effect; `:set`
+    /// runs this inside `relayout` since the filter may then keep other lines.
+    fn refresh_search_hits(&mut self) {
+        if let Some(filter) = &mut self.filter
+            && let Ok(pattern) = Pattern::new(&filter.pattern.query, &self.options)
+        {
+            filter.pattern = pattern;
+        }
+        if let Some(pattern) = self.search_pattern.take() {
+            let pattern = Pattern::new(&pattern.query, &self.options).unwrap_or(pattern);
+            self.search_hits = self.find_hits(&pattern);
+            self.search_pattern = Some(pattern);
+            self.current_hit = None;
+        }
+        for highlight in &mut self.highlights {
+            if let Ok(pattern) = Pattern::new(&highlight.pattern.query, &self.options) {
+                highlight.pattern = pattern;
+            }
+        }
+    }
+
+    fn clear_search(&mut self) {
+        self.search_pattern = None;
+        self.search_hits.clear();
+        self.current_hit = None;
+    }
+
+    /// Jumps to the next hit in the direction of the last search.
+    fn next_hit(&mut self, height: u16) {
+        self.jump_from_cursor(self.search_backward, true);
+        self.ensure_visible(height);
+    }
+
+    /// Jumps to the next hit against the direction of the last search.
+    fn prev_hit(&mut self, height: u16) {
+        self.jump_from_cursor(!self.search_backward, true);
+        self.ensure_visible(height);
+    }
+}
+
+#[derive(Parser)]
+struct Cli {
+    /// Print the file without launching the TUI
+    #[arg(long)]
+    headless: bool,
+
+    /// Custom command definitions in the form <name>:<template>. Suffix the
+    /// name with `!` to show the command's output inline after the selection,
+    /// with `$` to run the template through `sh -c`, with `|` to pipe the
+    /// selection or file to its stdin and with `@` to run it in the foreground
+    /// with the terminal, e.g. for an editor
+    #[arg(long = "command", action = clap::ArgAction::Append)]
+    commands: Vec<CommandSpec>,
+
+    /// Path to the file to view
+    path: PathBuf,
+}
+
+fn main() -> Result<()> {
+    let args = Cli::parse();
+    let mut file = File::open(&args.path)?;
+    let mut content = String::new();
+    file.read_to_string(&mut content)?;
+
+    if args.headless {
+        println!("{}", content);
+        return Ok(());
+    }
+
+    // setup terminal
+    enable_raw_mode()?;
+    let mut stdout = io::stdout();
+    execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
+    let backend = CrosstermBackend::new(stdout);
+    let mut terminal = Terminal::new(backend)?;
+
+    let res = run_app(&mut terminal, args.path.clone(), content, args.commands);
+
+    // restore terminal
+    disable_raw_mode()?;
+    execute!(
+        terminal.backend_mut(),
+        LeaveAlternateScreen,
+        DisableMouseCapture
+    )?;
+    terminal.show_cursor()?;
+
+    if let Err(err) = res {
+        println!("{:?}", err)
+    }
+
+    Ok(())
+}
+
+/// Gives the terminal back to the shell while `f` runs: raw mode is turned off
+/// and the alternate screen left, then both are restored and the viewer is
+/// redrawn from scratch.
+fn suspend<B: Backend, T>(terminal: &mut Terminal<B>, f: impl FnOnce() -> T) -> io::Result<T> {
+    disable_raw_mode()?;
+    execute!(io::stdout(), LeaveAlternateScreen, DisableMouseCapture)?;
+    terminal.show_cursor()?;
+    let result = f();
+    enable_raw_mode()?;
+    execute!(io::stdout(), EnterAlternateScreen, EnableMouseCapture)?;
+    terminal.clear()?;
+    Ok(result)
+}
+
+fn run_app<B: Backend>(
+    terminal: &mut Terminal<B>,
+    path: PathBuf,
+    content: String,
+    commands: Vec<CommandSpec>,
 ) -> io::Result<()> {
     let mut app = App::new(path.clone(), content, commands);
+    app.load_annotations();
+    if let Some(history_path) = history::default_path() {
+        app.history = SearchHistory::load(history_path);
+    }
     let (tx, rx) = mpsc::channel();
     let mut watcher: RecommendedWatcher = RecommendedWatcher::new(
         move |res| {
@@ -604,6 +1616,7 @@ fn run_app<B: Backend>(
     let mut ctx = commands::Context {
         height: 0,
         pending_g: false,
+        pending_prefix: None,
     };
     loop {
         terminal.draw(|f| ui(f, &app))?;
@@ -613,7 +1626,7 @@ fn run_app<B: Backend>(
             if matches!(event.kind, EventKind::Modify(_)) {
                 let mut new_content = String::new();
                 File::open(&app.path)?.read_to_string(&mut new_content)?;
-                app.doc.reload(new_content);
+                app.reload(new_content);
                 app.cursor_y = app
                     .cursor_y
                     .min(app.display_lines().len().saturating_sub(1));
@@ -622,41 +1635,65 @@ fn run_app<B: Backend>(
                     .min(app.display_lines().len().saturating_sub(1) as u16);
                 let height = terminal.size()?.height.saturating_sub(1);
                 app.ensure_visible(height);
-                app.overlays.clear();
-                app.search_hits.clear();
-                app.current_hit = None;
             }
         }
 
-        if event::poll(Duration::from_millis(100))? {
-            if let Event::Key(key) = event::read()? {
-                ctx.height = terminal.size()?.height.saturating_sub(1);
+        while let Ok(done) = app.job_rx.try_recv() {
+            app.finish_job(done);
+        }
 
-                let mode = app.mode.clone();
-                let quit = match mode {
-                    Mode::Normal => {
-                        app.mode = Mode::Normal;
-                        keymaps::normal::handle(&mut app, key, &mut ctx)
-                    }
-                    Mode::Visual => {
-                        app.mode = Mode::Visual;
-                        keymaps::visual::handle(&mut app, key, &mut ctx)
-                    }
-                    Mode::VisualLine => {
-                        app.mode = Mode::VisualLine;
-                        keymaps::visual::handle(&mut app, key, &mut ctx)
-                    }
-                    Mode::Command(_) => keymaps::command::handle(&mut app, key, &mut ctx),
-                    Mode::Search(_) => keymaps::search::handle(&mut app, key, &mut ctx),
-                    Mode::Help => {
-                        app.mode = Mode::Help;
-                        keymaps::help::handle(&mut app, key, &mut ctx)
-                    }
-                };
+        if let Some(command) = app.foreground.take() {
+            let status = suspend(terminal, || {
+                jobs::run_foreground(&command.argv, command.input)
+            })?;
+            match status {
+                Ok(status) if status.success() => {}
+                Ok(status) => {
+                    app.message = Some(match status.code() {
+                        Some(code) => format!("{} exited with {code}", command.name),
+                        None => format!("{} was killed", command.name),
+                    })
+                }
+                Err(err) => app.message = Some(format!("Cannot run {}: {err}", command.name)),
+            }
+            continue;
+        }
 
-                if quit {
-                    return Ok(());
+        if event::poll(Duration::from_millis(100))?
+            && let Event::Key(key) = event::read()?
+        {
+            ctx.height = terminal.size()?.height.saturating_sub(1);
+            app.message = None;
+
+            let mode = app.mode.clone();
+            let quit = match mode {
+                Mode::Normal => {
+                    app.mode = Mode::Normal;
+                    keymaps::normal::handle(&mut app, key, &mut ctx)
+                }
+                Mode::Visual => {
+                    app.mode = Mode::Visual;
+                    keymaps::visual::handle(&mut app, key, &mut ctx)
+                }
+                Mode::VisualLine => {
+                    app.mode = Mode::VisualLine;
+                    keymaps::visual::handle(&mut app, key, &mut ctx)
+                }
+                Mode::Command(_) => keymaps::command::handle(&mut app, key, &mut ctx),
+                Mode::Search(_) => keymaps::search::handle(&mut app, key, &mut ctx),
+                Mode::Note(_) => keymaps::note::handle(&mut app, key, &mut ctx),
+                Mode::Finder(_) => keymaps::finder::handle(&mut app, key, &mut ctx),
+                Mode::Help => {
+                    app.mode = Mode::Help;
+                    keymaps::help::handle(&mut app, key, &mut ctx)
                 }
+            };
+
+            if quit {
+                for job in &app.jobs {
+                    job.cancel();
+                }
+                return Ok(());
             }
         }
     }
@@ -681,28 +1718,73 @@ fn ui(f: &mut Frame, app: &App) {
     let selection = app
         .selection_start
         .map(|s| (s, (app.cursor_y, app.cursor_x)));
-    let line_mode = matches!(app.mode, Mode::VisualLine);
-    let lines: Vec<Line> = app
-        .display_lines()
+    let line_mode = app.selection_linewise;
+    let display_lines = app.display_lines();
+    // while filtering, original line numbers are shown in a gutter
+    let number_width = match app.filter {
+        Some(_) => app.doc.lines.len().max(1).to_string().len() + 1,
+        None => 0,
+    };
+    let lines: Vec<Line> = display_lines
         .iter()
         .enumerate()
         .map(|(i, l)| {
             highlight_line(
                 l.text(),
                 i,
-                app.search_query.as_deref(),
+                l.overlay_info(),
+                if l.overlay_info().is_none() || app.options.overlay_search {
+                    &app.highlights
+                } else {
+                    &[]
+                },
+                app.search_pattern
+                    .as_ref()
+                    .filter(|_| app.options.overlay_search || l.overlay_info().is_none()),
                 selection,
                 line_mode,
             )
         })
         .collect();
+    if number_width > 0 {
+        let numbers: Vec<Line> = display_lines
+            .iter()
+            .map(|l| {
+                let number = match l {
+                    DisplayLine::Original(_, n) => (n + 1).to_string(),
+                    DisplayLine::Overlay { .. } => String::new(),
+                };
+                Line::styled(
+                    format!("{:>w$} ", number, w = number_width - 1),
+                    Style::default().fg(Color::DarkGray),
+                )
+            })
+            .collect();
+        let gutter_area = Rect {
+            width: (number_width as u16).min(main_area.width),
+            ..main_area
+        };
+        f.render_widget(
+            Paragraph::new(Text::from(numbers)).scroll((app.scroll, 0)),
+            gutter_area,
+        );
+    }
+    let text_area = Rect {
+        x: main_area.x + number_width as u16,
+        width: main_area.width.saturating_sub(number_width as u16),
+        ..main_area
+    };
     let text = Text::from(lines);
     let paragraph = Paragraph::new(text)
         .wrap(Wrap { trim: true })
         .scroll((app.scroll, 0));
-    f.render_widget(paragraph, main_area);
+    f.render_widget(paragraph, text_area);
     let cursor_y = main_area.y + (app.cursor_y as u16).saturating_sub(app.scroll);
-    let cursor_x = main_area.x + app.cursor_x as u16;
+    let gutter = match display_lines.get(app.cursor_y) {
+        Some(DisplayLine::Overlay { .. }) => overlay::GUTTER_WIDTH,
+        _ => 0,
+    };
+    let cursor_x = main_area.x + (app.cursor_x + gutter + number_width) as u16;
     f.set_cursor_position((cursor_x, cursor_y));
 
     let cmd_area = Rect {
@@ -720,7 +1802,8 @@ fn ui(f: &mut Frame, app: &App) {
             f.set_cursor_position((cmd_area.x + 1 + cmd.len() as u16, cmd_area.y));
         }
         Mode::Search(query) => {
-            let text = format!("/{}", query);
+            let prompt = if app.search_backward { '?' } else { '/' };
+            let text = format!("{}{}", prompt, query);
             let paragraph = Paragraph::new(text);
             f.render_widget(paragraph, cmd_area);
             f.set_cursor_position((cmd_area.x + 1 + query.len() as u16, cmd_area.y));
@@ -733,11 +1816,83 @@ fn ui(f: &mut Frame, app: &App) {
             let paragraph = Paragraph::new("-- VISUAL LINE --");
             f.render_widget(paragraph, cmd_area);
         }
+        Mode::Note(_) => {
+            let paragraph = Paragraph::new("-- NOTE -- Esc to save, Ctrl-C to cancel");
+            f.render_widget(paragraph, cmd_area);
+        }
         _ => {
-            let blank = Paragraph::new("");
-            f.render_widget(blank, cmd_area);
+            let message = Paragraph::new(app.message.as_deref().unwrap_or(""));
+            f.render_widget(message, cmd_area);
+        }
+    }
+
+    if !matches!(
+        app.mode,
+        Mode::Command(_) | Mode::Search(_) | Mode::Note(_) | Mode::Finder(_)
+    ) {
+        let status: Vec<String> = [app.job_status(), app.overlay_status()]
+            .into_iter()
+            .flatten()
+            .collect();
+        if !status.is_empty() {
+            let paragraph = Paragraph::new(status.join("  ")).alignment(Alignment::Right);
+            f.render_widget(paragraph, cmd_area);
+        }
+    }
+
+    if let Mode::Finder(finder) = &app.mode {
+        render_finder(f, app, finder, main_area);
+    }
+}
+
+/// Draws the line finder as a bordered popup over the middle of `area`: the
+/// query on top and the ranked lines below it, matched characters in yellow.
+fn render_finder(f: &mut Frame, app: &App, finder: &LineFinder, area: Rect) {
+    let width = (area.width * 4 / 5).max(20).min(area.width);
+    let height = (area.height * 4 / 5).max(4).min(area.height);
+    let popup = Rect {
+        x: area.x + (area.width - width) / 2,
+        y: area.y + (area.height - height) / 2,
+        width,
+        height,
+    };
+    let title = format!(" Lines {}/{} ", finder.results.len(), app.doc.lines.len());
+    let block = Block::bordered().title(title);
+    let inner = block.inner(popup);
+    f.render_widget(Clear, popup);
+    f.render_widget(block, popup);
+
+    let rows = inner.height.saturating_sub(1) as usize;
+    let offset = (finder.selected + 1).saturating_sub(rows);
+    let number_width = app.doc.lines.len().max(1).to_string().len();
+    let mut lines = vec![Line::from(format!("> {}", finder.query))];
+    for (i, &n) in finder.results.iter().enumerate().skip(offset).take(rows) {
+        let Some(text) = app.doc.lines.get(n) else {
+            continue;
+        };
+        let positions = finder::fuzzy_match(&finder.query, text)
+            .map(|m| m.positions)
+            .unwrap_or_default();
+        let mut spans = vec![Span::styled(
+            format!("{:>number_width$} ", n + 1),
+            Style::default().fg(Color::DarkGray),
+        )];
+        for (at, c) in text.char_indices() {
+            let style = if positions.contains(&at) {
+                Style::default().fg(Color::Yellow)
+            } else {
+                Style::default()
+            };
+            spans.push(Span::styled(c.to_string(), style));
+        }
+        let mut line = Line::from(spans);
+        if i == finder.selected {
+            line = line.style(Style::default().add_modifier(Modifier::REVERSED));
         }
+        lines.push(line);
     }
+    f.render_widget(Paragraph::new(Text::from(lines)), inner);
+    f.set_cursor_position((inner.x + 2 + finder.query.len() as u16, inner.y));
 }
 #[cfg(test)]
 mod tests {
@@ -746,6 +1901,14 @@ mod tests {
     use ratatui::crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
     use ratatui::{Terminal, backend::TestBackend};
 
+    /// Lets the custom commands started so far finish, as the main loop would.
+    fn wait_for_jobs(app: &mut App) {
+        while !app.jobs.is_empty() {
+            let done = app.job_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+            app.finish_job(done);
+        }
+    }
+
     #[test]
     fn initial_ui_snapshot() {
         let content = "hello\nworld".to_string();
@@ -801,6 +1964,7 @@ mod tests {
         let mut ctx = commands::Context {
             height,
             pending_g: false,
+            pending_prefix: None,
         };
         let key = KeyEvent::new(KeyCode::Char(':'), KeyModifiers::NONE);
         keymaps::normal::handle(&mut app, key, &mut ctx);
@@ -818,6 +1982,7 @@ mod tests {
         let mut ctx = commands::Context {
             height,
             pending_g: false,
+            pending_prefix: None,
         };
         let key = KeyEvent::new(KeyCode::Char('/'), KeyModifiers::NONE);
         keymaps::normal::handle(&mut app, key, &mut ctx);
@@ -847,6 +2012,7 @@ mod tests {
         let mut ctx = commands::Context {
             height,
             pending_g: false,
+            pending_prefix: None,
         };
         let key = KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE);
         keymaps::command::handle(&mut app, key, &mut ctx);
@@ -928,12 +2094,1092 @@ mod tests {
         let content = "line1\nline2".to_string();
         let mut app = App::new(PathBuf::from("file.txt"), content, Vec::new());
         app.overlays = vec![OverlayItem {
+            id: 0,
             after_line: 0,
             content: vec!["| note".to_string()],
+            kind: OverlayKind::Info,
+            collapsed: false,
         }];
         let backend = TestBackend::new(20, 5);
         let mut terminal = Terminal::new(backend).unwrap();
         terminal.draw(|f| ui(f, &app)).unwrap();
         assert_snapshot!("overlay_render_inline", terminal.backend());
     }
+
+    #[test]
+    fn capture_command_inserts_overlay() {
+        let content = "line1\nline2\nline3".to_string();
+        let spec: CommandSpec = "say!:echo hello {args}".parse().unwrap();
+        assert!(spec.capture);
+        let mut app = App::new(PathBuf::new(), content, vec![spec]);
+        app.cursor_y = 1;
+        app.mode = Mode::Command("say world".into());
+        let mut ctx = commands::Context {
+            height: 4,
+            pending_g: false,
+            pending_prefix: None,
+        };
+        commands::EditorCommand::CommandSubmit.run(&mut app, &mut ctx);
+        wait_for_jobs(&mut app);
+
+        assert_eq!(app.overlays.len(), 1);
+        assert_eq!(app.overlays[0].after_line, 1);
+        assert_eq!(app.overlays[0].content, vec!["hello world".to_string()]);
+        let texts: Vec<&str> = app.display_lines().iter().map(|l| l.text()).collect();
+        assert_eq!(texts, vec!["line1", "line2", "hello world", "line3"]);
+    }
+
+    #[test]
+    fn capture_job_finishing_keeps_note_draft_and_follows_reload() {
+        let content = "a\nb\nc".to_string();
+        let spec: CommandSpec = "say!:sh -c 'sleep 0.2; echo done'".parse().unwrap();
+        let mut app = App::new(PathBuf::new(), content, vec![spec]);
+        let mut ctx = commands::Context {
+            height: 5,
+            pending_g: false,
+            pending_prefix: None,
+        };
+        app.cursor_y = 2;
+        app.mode = Mode::Command("say".into());
+        commands::EditorCommand::CommandSubmit.run(&mut app, &mut ctx);
+        // line `c` moves down by one before the job finishes
+        app.reload("new\na\nb\nc".to_string());
+        app.cursor_y = 0;
+        keymaps::normal::handle(&mut app, KeyEvent::from(KeyCode::Char('a')), &mut ctx);
+        wait_for_jobs(&mut app);
+        for c in "hi".chars() {
+            keymaps::note::handle(&mut app, KeyEvent::from(KeyCode::Char(c)), &mut ctx);
+        }
+        keymaps::note::handle(&mut app, KeyEvent::from(KeyCode::Esc), &mut ctx);
+
+        let texts: Vec<&str> = app.display_lines().iter().map(|l| l.text()).collect();
+        assert_eq!(texts, vec!["new", "hi", "a", "b", "c", "done"]);
+    }
+
+    #[test]
+    fn capture_command_parses_overlay_records() {
+        let content = "a\nb\nc".to_string();
+        let spec: CommandSpec = "review!:printf \"{args}\"".parse().unwrap();
+        let mut app = App::new(PathBuf::new(), content, vec![spec]);
+        let records = r#"{"after_line":3,"content":["third"]}\n{"after_line":1,"content":["first","more"],"kind":"warning"}\n"#;
+        app.mode = Mode::Command(format!("review {records}"));
+        let mut ctx = commands::Context {
+            height: 4,
+            pending_g: false,
+            pending_prefix: None,
+        };
+        commands::EditorCommand::CommandSubmit.run(&mut app, &mut ctx);
+        wait_for_jobs(&mut app);
+
+        let texts: Vec<&str> = app.display_lines().iter().map(|l| l.text()).collect();
+        assert_eq!(texts, vec!["a", "first", "more", "b", "c", "third"]);
+    }
+
+    #[test]
+    fn overlay_records_require_every_line() {
+        assert!(overlay::parse_records("{\"after_line\":1,\"content\":[]}\nplain").is_none());
+        assert!(overlay::parse_records("").is_none());
+    }
+
+    #[test]
+    fn overlay_kinds_render_styled() {
+        let content = "line1\nline2".to_string();
+        let mut app = App::new(PathBuf::new(), content, Vec::new());
+        app.overlays = vec![
+            OverlayItem {
+                id: 0,
+                after_line: 0,
+                content: vec!["bad".to_string()],
+                kind: OverlayKind::Error,
+                collapsed: false,
+            },
+            OverlayItem {
+                id: 0,
+                after_line: 1,
+                content: vec!["added".to_string()],
+                kind: OverlayKind::DiffAdded,
+                collapsed: false,
+            },
+        ];
+        let backend = TestBackend::new(20, 5);
+        let mut terminal = Terminal::new(backend).unwrap();
+        terminal.draw(|f| ui(f, &app)).unwrap();
+        assert_debug_snapshot!("overlay_kinds_render_styled", terminal.backend().buffer());
+    }
+
+    #[test]
+    fn reload_reanchors_overlays() {
+        let content = "a\nb\nc\nd".to_string();
+        let mut app = App::new(PathBuf::new(), content, Vec::new());
+        for (after_line, text) in [(0, "on a"), (1, "on b"), (3, "on d")] {
+            app.insert_overlay(OverlayItem {
+                id: 0,
+                after_line,
+                content: vec![text.to_string()],
+                kind: OverlayKind::Note,
+                collapsed: false,
+            });
+        }
+
+        app.reload("new\na\nc\nd\ne".to_string());
+
+        let texts: Vec<&str> = app.display_lines().iter().map(|l| l.text()).collect();
+        assert_eq!(texts, vec!["new", "a", "on a", "c", "d", "on d", "e"]);
+
+        // an edited anchor keeps its overlay, surplus lines of the hunk don't
+        app.reload("new\nA\nc\nD\ne".to_string());
+        let texts: Vec<&str> = app.display_lines().iter().map(|l| l.text()).collect();
+        assert_eq!(texts, vec!["new", "A", "on a", "c", "D", "on d", "e"]);
+        app.reload("new\nA\nc\ne".to_string());
+        let texts: Vec<&str> = app.display_lines().iter().map(|l| l.text()).collect();
+        assert_eq!(texts, vec!["new", "A", "on a", "c", "e"]);
+    }
+
+    #[test]
+    fn bracket_o_walks_overlays() {
+        let content = "a\nb\nc\nd".to_string();
+        let mut app = App::new(PathBuf::new(), content, Vec::new());
+        for after_line in [0, 2] {
+            app.insert_overlay(OverlayItem {
+                id: 0,
+                after_line,
+                content: vec!["one".to_string(), "two".to_string()],
+                kind: OverlayKind::Info,
+                collapsed: false,
+            });
+        }
+        let mut ctx = commands::Context {
+            height: 10,
+            pending_g: false,
+            pending_prefix: None,
+        };
+        let press = |app: &mut App, ctx: &mut commands::Context, c: char| {
+            let key = KeyEvent::new(KeyCode::Char(c), KeyModifiers::NONE);
+            keymaps::normal::handle(app, key, ctx);
+        };
+
+        assert_eq!(app.overlay_status().as_deref(), Some("2 overlays"));
+        press(&mut app, &mut ctx, ']');
+        press(&mut app, &mut ctx, 'o');
+        assert_eq!(app.cursor_y, 1);
+        assert_eq!(app.overlay_status().as_deref(), Some("overlay 1/2"));
+        press(&mut app, &mut ctx, ']');
+        press(&mut app, &mut ctx, 'o');
+        assert_eq!(app.cursor_y, 5);
+        press(&mut app, &mut ctx, '[');
+        press(&mut app, &mut ctx, 'o');
+        assert_eq!(app.cursor_y, 1);
+    }
+
+    #[test]
+    fn za_collapses_overlay_under_cursor() {
+        let content = "line1\nline2".to_string();
+        let mut app = App::new(PathBuf::new(), content, Vec::new());
+        app.insert_overlay(OverlayItem {
+            id: 0,
+            after_line: 0,
+            content: vec![
+                "first".to_string(),
+                "second".to_string(),
+                "third".to_string(),
+            ],
+            kind: OverlayKind::Warning,
+            collapsed: false,
+        });
+        app.cursor_y = 2;
+        let mut ctx = commands::Context {
+            height: 5,
+            pending_g: false,
+            pending_prefix: None,
+        };
+        for c in ['z', 'a'] {
+            let key = KeyEvent::new(KeyCode::Char(c), KeyModifiers::NONE);
+            keymaps::normal::handle(&mut app, key, &mut ctx);
+        }
+        assert!(app.overlays[0].collapsed);
+        assert_eq!(app.cursor_y, 1);
+        assert_eq!(app.display_lines().len(), 3);
+
+        let backend = TestBackend::new(30, 5);
+        let mut terminal = Terminal::new(backend).unwrap();
+        terminal.draw(|f| ui(f, &app)).unwrap();
+        assert_snapshot!("overlay_collapsed", terminal.backend());
+
+        app.set_all_overlays_collapsed(false, 5);
+        assert_eq!(app.display_lines().len(), 5);
+        assert_eq!(app.cursor_y, 1);
+    }
+
+    #[test]
+    fn motions_and_search_skip_overlays() {
+        let content = "alpha beta\n\n  gamma beta".to_string();
+        let mut app = App::new(PathBuf::new(), content, Vec::new());
+        app.insert_overlay(OverlayItem {
+            id: 0,
+            after_line: 0,
+            content: vec!["beta note".to_string(), String::new()],
+            kind: OverlayKind::Note,
+            collapsed: false,
+        });
+
+        app.cursor_x = 6;
+        app.move_word_forward();
+        assert_eq!((app.cursor_y, app.cursor_x), (4, 2));
+        app.goto_first_line();
+        app.move_paragraph_down();
+        assert_eq!(app.cursor_y, 3);
+
+        app.set_search_query("beta".into());
+        assert_eq!(app.search_hits, vec![(0, 6), (4, 8)]);
+
+        app.mode = Mode::Command("set overlaysearch overlaymotion!".into());
+        let mut ctx = commands::Context {
+            height: 5,
+            pending_g: false,
+            pending_prefix: None,
+        };
+        commands::EditorCommand::CommandSubmit.run(&mut app, &mut ctx);
+        assert_eq!(app.search_hits, vec![(0, 6), (1, 0), (4, 8)]);
+        app.goto_first_line();
+        app.move_paragraph_down();
+        assert_eq!(app.cursor_y, 2);
+
+        app.mode = Mode::Command("set bogus".into());
+        commands::EditorCommand::CommandSubmit.run(&mut app, &mut ctx);
+        assert_eq!(app.message.as_deref(), Some("Unknown option: bogus"));
+    }
+
+    #[test]
+    fn annotations_round_trip_through_sidecar() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("review.txt");
+        let mut app = App::new(path.clone(), "a\nb\nc".to_string(), Vec::new());
+        for (after_line, kind) in [(1, OverlayKind::Warning), (2, OverlayKind::Note)] {
+            app.insert_overlay(OverlayItem {
+                id: 0,
+                after_line,
+                content: vec![format!("{kind:?}")],
+                kind,
+                collapsed: false,
+            });
+        }
+        app.mode = Mode::Command("annotations save".into());
+        let mut ctx = commands::Context {
+            height: 5,
+            pending_g: false,
+            pending_prefix: None,
+        };
+        commands::EditorCommand::CommandSubmit.run(&mut app, &mut ctx);
+        assert!(annotations::sidecar_path(&path).exists());
+
+        // "b" moved down a line and "c" was deleted.
+        let mut app = App::new(path.clone(), "new\na\nb".to_string(), Vec::new());
+        app.load_annotations();
+        assert_eq!(
+            app.message.as_deref(),
+            Some("Loaded 1 annotations, 1 dropped")
+        );
+        assert_eq!(app.overlays.len(), 1);
+        assert_eq!(app.overlays[0].after_line, 2);
+        assert_eq!(app.overlays[0].kind, OverlayKind::Warning);
+
+        // a sidecar copied next to another file is not applied to it
+        let other = dir.path().join("other.txt");
+        std::fs::copy(
+            annotations::sidecar_path(&path),
+            annotations::sidecar_path(&other),
+        )
+        .unwrap();
+        let mut app = App::new(other, "new\na\nb".to_string(), Vec::new());
+        app.load_annotations();
+        assert!(app.overlays.is_empty());
+        assert_eq!(
+            app.message,
+            Some(format!(
+                "Cannot load annotations: saved for {}",
+                path.display()
+            ))
+        );
+    }
+
+    #[test]
+    fn note_mode_adds_edits_and_deletes_notes() {
+        let mut app = App::new(PathBuf::new(), "a\nb".to_string(), Vec::new());
+        let mut ctx = commands::Context {
+            height: 5,
+            pending_g: false,
+            pending_prefix: None,
+        };
+        fn send(app: &mut App, ctx: &mut commands::Context, code: KeyCode, mods: KeyModifiers) {
+            let key = KeyEvent::new(code, mods);
+            match app.mode {
+                Mode::Note(_) => keymaps::note::handle(app, key, ctx),
+                _ => keymaps::normal::handle(app, key, ctx),
+            };
+        }
+        fn type_str(app: &mut App, ctx: &mut commands::Context, text: &str) {
+            for c in text.chars() {
+                send(app, ctx, KeyCode::Char(c), KeyModifiers::NONE);
+            }
+        }
+
+        type_str(&mut app, &mut ctx, "ahi");
+        send(&mut app, &mut ctx, KeyCode::Enter, KeyModifiers::NONE);
+        type_str(&mut app, &mut ctx, "there");
+        send(&mut app, &mut ctx, KeyCode::Esc, KeyModifiers::NONE);
+        assert!(matches!(app.mode, Mode::Normal));
+        assert_eq!(app.overlays[0].after_line, 0);
+        assert_eq!(app.overlays[0].content, vec!["hi", "there"]);
+        assert_eq!(app.overlays[0].kind, OverlayKind::Note);
+        assert_eq!(app.cursor_y, 2);
+
+        type_str(&mut app, &mut ctx, "a!");
+        assert_eq!(app.overlays[0].content, vec!["hi", "there!"]);
+        send(
+            &mut app,
+            &mut ctx,
+            KeyCode::Char('c'),
+            KeyModifiers::CONTROL,
+        );
+        assert_eq!(app.overlays[0].content, vec!["hi", "there"]);
+
+        type_str(&mut app, &mut ctx, "do");
+        assert!(app.overlays.is_empty());
+
+        // a reload that drops an earlier overlay doesn't move the draft
+        app.insert_overlay(OverlayItem {
+            id: 0,
+            after_line: 0,
+            content: vec!["on a".to_string()],
+            kind: OverlayKind::Info,
+            collapsed: false,
+        });
+        app.cursor_y = 2;
+        type_str(&mut app, &mut ctx, "ax");
+        app.reload("b".to_string());
+        type_str(&mut app, &mut ctx, "y");
+        assert_eq!(app.overlays.len(), 1);
+        assert_eq!(app.overlays[0].content, vec!["xy"]);
+        send(
+            &mut app,
+            &mut ctx,
+            KeyCode::Char('c'),
+            KeyModifiers::CONTROL,
+        );
+        assert!(app.overlays.is_empty());
+    }
+
+    #[test]
+    fn export_review_interleaves_annotations() {
+        let lines: Vec<String> = ["fn main() {", "    run();", "}"]
+            .iter()
+            .map(|l| l.to_string())
+            .collect();
+        let overlays = vec![OverlayItem {
+            id: 0,
+            after_line: 1,
+            content: vec!["handle the error".to_string(), "here".to_string()],
+            kind: OverlayKind::Warning,
+            collapsed: false,
+        }];
+        let path = PathBuf::from("src/main.rs");
+
+        let markdown = export::render(export::ReviewFormat::Markdown, &path, &lines, &overlays);
+        assert_eq!(
+            markdown,
+            "# Review of `src/main.rs`\n\n```\n1 | fn main() {\n2 |     run();\n```\n\n\
+             > **warning** (line 2):\n> handle the error\n> here\n\n```\n3 | }\n```\n"
+        );
+
+        let diff = export::render(export::ReviewFormat::Diff, &path, &lines, &overlays);
+        assert_eq!(
+            diff,
+            "--- a/src/main.rs\n+++ b/src/main.rs\n@@ -1,3 +1,5 @@\n fn main() {\n     run();\n\
+             +> warning (line 2): handle the error\n+> here\n }\n"
+        );
+
+        let added = vec![OverlayItem {
+            id: 0,
+            after_line: 0,
+            content: vec!["new file".to_string()],
+            kind: OverlayKind::DiffAdded,
+            collapsed: false,
+        }];
+        let diff = export::render(export::ReviewFormat::Diff, &path, &[], &added);
+        assert_eq!(
+            diff,
+            "--- a/src/main.rs\n+++ b/src/main.rs\n@@ -0,0 +1,1 @@\n+> diff-added (line 1): new file\n"
+        );
+    }
+
+    #[test]
+    fn regex_search_hits_and_highlights_agree() {
+        let content = "fn main() {\n    let fn_x = 1;\n}\nfn helper(a: u8)".to_string();
+        let mut app = App::new(PathBuf::new(), content, Vec::new());
+        app.set_search_query("\\v^fn \\w+\\(".into());
+        assert_eq!(app.search_hits, vec![(0, 0), (3, 0)]);
+        let pattern = app.search_pattern.as_ref().unwrap();
+        assert_eq!(pattern.matches("fn helper(a: u8)"), vec![(0, 10)]);
+
+        app.set_search_query("\\v(unclosed".into());
+        assert_eq!(
+            app.message.as_deref(),
+            Some("Invalid pattern: unclosed group")
+        );
+        assert_eq!(app.search_hits, vec![(0, 0), (3, 0)]);
+
+        // a bare prefix would match everywhere, so it clears the search
+        app.set_search_query(search::REGEX_PREFIX.into());
+        assert!(app.search_pattern.is_none());
+        assert!(app.search_hits.is_empty());
+        app.set_filter(search::REGEX_PREFIX, false, 5);
+        assert!(app.filter.is_none());
+    }
+
+    #[test]
+    fn search_survives_reload() {
+        let mut app = App::new(PathBuf::new(), "foo\nbar".to_string(), Vec::new());
+        app.set_search_query("foo".into());
+        app.reload("bar\nfoo\nfoo".to_string());
+        assert_eq!(app.search_hits, vec![(1, 0), (2, 0)]);
+        app.next_hit(5);
+        assert_eq!(app.cursor_y, 1);
+        assert_eq!(app.message.as_deref(), Some("/foo [1/2]"));
+    }
+
+    #[test]
+    fn ignorecase_smartcase_and_overrides() {
+        let content = "error\nERROR\nError".to_string();
+        let mut app = App::new(PathBuf::new(), content, Vec::new());
+        let hit_lines = |app: &App| app.search_hits.iter().map(|h| h.0).collect::<Vec<_>>();
+
+        app.set_search_query("error".into());
+        assert_eq!(hit_lines(&app), vec![0]);
+        app.set_search_query("\\cerror".into());
+        assert_eq!(hit_lines(&app), vec![0, 1, 2]);
+
+        app.options.apply("ignorecase smartcase").unwrap();
+        app.set_search_query("error".into());
+        assert_eq!(hit_lines(&app), vec![0, 1, 2]);
+        app.set_search_query("Error".into());
+        assert_eq!(hit_lines(&app), vec![2]);
+        app.set_search_query("error\\C".into());
+        assert_eq!(hit_lines(&app), vec![0]);
+        app.set_search_query("\\vE\\w+\\c".into());
+        assert_eq!(hit_lines(&app), vec![0, 1, 2]);
+
+        app.options.apply("noic").unwrap();
+        app.refresh_search_hits();
+        assert_eq!(hit_lines(&app), vec![0, 1, 2]);
+
+        // a bad argument leaves every setting alone
+        assert_eq!(
+            app.options.apply("ic bogus"),
+            Err("Unknown option: bogus".to_string())
+        );
+        assert!(!app.options.ignore_case);
+
+        // flags alone leave nothing to search for
+        for query in ["\\c", "\\C", "\\v\\c"] {
+            app.set_search_query(query.into());
+            assert!(app.search_pattern.is_none(), "{query}");
+        }
+    }
+
+    #[test]
+    fn incremental_search_previews_and_esc_restores() {
+        let content: String = (1..=20).map(|i| format!("line {i}\n")).collect();
+        let mut app = App::new(PathBuf::new(), content, Vec::new());
+        let mut ctx = commands::Context {
+            height: 4,
+            pending_g: false,
+            pending_prefix: None,
+        };
+        app.cursor_y = 2;
+        let press = |app: &mut App, ctx: &mut commands::Context, code: KeyCode| {
+            let key = KeyEvent::new(code, KeyModifiers::NONE);
+            match app.mode {
+                Mode::Search(_) => keymaps::search::handle(app, key, ctx),
+                _ => keymaps::normal::handle(app, key, ctx),
+            };
+        };
+
+        press(&mut app, &mut ctx, KeyCode::Char('/'));
+        press(&mut app, &mut ctx, KeyCode::Char('1'));
+        assert_eq!(app.cursor_y, 9);
+        assert!(app.search_pattern.is_some());
+        press(&mut app, &mut ctx, KeyCode::Char('5'));
+        assert_eq!((app.cursor_y, app.scroll), (14, 11));
+        press(&mut app, &mut ctx, KeyCode::Backspace);
+        assert_eq!(app.cursor_y, 9);
+
+        press(&mut app, &mut ctx, KeyCode::Esc);
+        assert!(matches!(app.mode, Mode::Normal));
+        assert_eq!((app.cursor_y, app.cursor_x, app.scroll), (2, 0, 0));
+        assert!(app.search_pattern.is_none());
+        assert!(app.search_hits.is_empty());
+
+        for c in "/line 2".chars() {
+            press(&mut app, &mut ctx, KeyCode::Char(c));
+        }
+        press(&mut app, &mut ctx, KeyCode::Enter);
+        assert_eq!(app.cursor_y, 19);
+        assert_eq!(app.current_hit, Some(1));
+    }
+
+    #[test]
+    fn backward_search_and_direction_aware_n() {
+        let content = "foo\nbar\nfoo\nbar\nfoo".to_string();
+        let mut app = App::new(PathBuf::new(), content, Vec::new());
+        let mut ctx = commands::Context {
+            height: 10,
+            pending_g: false,
+            pending_prefix: None,
+        };
+        app.cursor_y = 3;
+        for c in "?foo".chars() {
+            let key = KeyEvent::new(KeyCode::Char(c), KeyModifiers::NONE);
+            match app.mode {
+                Mode::Search(_) => keymaps::search::handle(&mut app, key, &mut ctx),
+                _ => keymaps::normal::handle(&mut app, key, &mut ctx),
+            };
+        }
+        let enter = KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE);
+        keymaps::search::handle(&mut app, enter, &mut ctx);
+        assert_eq!(app.cursor_y, 2);
+
+        app.next_hit(ctx.height);
+        assert_eq!(app.cursor_y, 0);
+        assert_eq!(app.message.as_deref(), Some("?foo [1/3]"));
+        app.next_hit(ctx.height);
+        assert_eq!(app.cursor_y, 4);
+        assert_eq!(
+            app.message.as_deref(),
+            Some("search hit TOP, continuing at BOTTOM [3/3]")
+        );
+
+        app.prev_hit(ctx.height);
+        assert_eq!(app.cursor_y, 0);
+        assert_eq!(
+            app.message.as_deref(),
+            Some("search hit BOTTOM, continuing at TOP [1/3]")
+        );
+    }
+
+    #[test]
+    fn search_status_messages() {
+        let content = "alpha\nbeta".to_string();
+        let mut app = App::new(PathBuf::new(), content, Vec::new());
+        app.next_hit(4);
+        assert_eq!(app.message.as_deref(), Some("No previous search pattern"));
+
+        app.set_search_query("gamma".into());
+        assert_eq!(app.message.as_deref(), Some("Pattern not found: gamma"));
+        assert_eq!(app.current_hit, None);
+
+        app.set_search_query("a".into());
+        assert_eq!(app.current_hit, Some(1));
+        app.next_hit(4);
+        assert_eq!(app.message.as_deref(), Some("/a [3/3]"));
+
+        let backend = TestBackend::new(20, 3);
+        let mut terminal = Terminal::new(backend).unwrap();
+        terminal.draw(|f| ui(f, &app)).unwrap();
+        assert_snapshot!("search_match_counter", terminal.backend());
+    }
+
+    #[test]
+    fn search_history_recalls_by_prefix_and_persists() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state/search_history");
+        let mut app = App::new(PathBuf::new(), "foo\nbar".to_string(), Vec::new());
+        app.history = SearchHistory::load(path.clone());
+        let mut ctx = commands::Context {
+            height: 4,
+            pending_g: false,
+            pending_prefix: None,
+        };
+        let mut press = |app: &mut App, keys: &str| {
+            for c in keys.chars() {
+                let code = match c {
+                    '\n' => KeyCode::Enter,
+                    '^' => KeyCode::Up,
+                    'v' => KeyCode::Down,
+                    c => KeyCode::Char(c),
+                };
+                let key = KeyEvent::new(code, KeyModifiers::NONE);
+                match app.mode {
+                    Mode::Search(_) => keymaps::search::handle(app, key, &mut ctx),
+                    _ => keymaps::normal::handle(app, key, &mut ctx),
+                };
+            }
+        };
+        let query = |app: &App| match &app.mode {
+            Mode::Search(q) => q.clone(),
+            _ => String::new(),
+        };
+
+        press(&mut app, "/foo\n/bar\n/fob\n");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "foo\nbar\nfob\n");
+
+        press(&mut app, "/f^");
+        assert_eq!(query(&app), "fob");
+        press(&mut app, "^");
+        assert_eq!(query(&app), "foo");
+        press(&mut app, "^");
+        assert_eq!(query(&app), "foo");
+        press(&mut app, "v");
+        assert_eq!(query(&app), "fob");
+        press(&mut app, "v");
+        assert_eq!(query(&app), "f");
+
+        let mut reloaded = SearchHistory::load(path);
+        assert_eq!(reloaded.older("").as_deref(), Some("fob"));
+    }
+
+    #[test]
+    fn filter_shows_matching_lines_with_numbers() {
+        let content = "INFO start\nERROR boom\nINFO more\nERROR again".to_string();
+        let mut app = App::new(PathBuf::new(), content, Vec::new());
+        app.insert_overlay(OverlayItem {
+            id: 0,
+            after_line: 1,
+            content: vec!["why".to_string()],
+            kind: OverlayKind::Note,
+            collapsed: false,
+        });
+        app.cursor_y = 3;
+        let mut ctx = commands::Context {
+            height: 5,
+            pending_g: false,
+            pending_prefix: None,
+        };
+        keymaps::normal::handle(
+            &mut app,
+            KeyEvent::new(KeyCode::Char('&'), KeyModifiers::NONE),
+            &mut ctx,
+        );
+        for c in "ERROR".chars() {
+            keymaps::command::handle(
+                &mut app,
+                KeyEvent::new(KeyCode::Char(c), KeyModifiers::NONE),
+                &mut ctx,
+            );
+        }
+        keymaps::command::handle(
+            &mut app,
+            KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE),
+            &mut ctx,
+        );
+        assert_eq!(app.cursor_y, 2);
+        assert_eq!(app.source_line(app.cursor_y), 3);
+
+        let backend = TestBackend::new(30, 5);
+        let mut terminal = Terminal::new(backend).unwrap();
+        terminal.draw(|f| ui(f, &app)).unwrap();
+        assert_snapshot!("filter_view", terminal.backend());
+
+        app.set_filter("ERROR", true, 5);
+        let texts: Vec<&str> = app.display_lines().iter().map(|l| l.text()).collect();
+        assert_eq!(texts, vec!["INFO start", "INFO more"]);
+        app.set_filter("", false, 5);
+        assert_eq!(app.display_lines().len(), 5);
+
+        // the filter follows :set ignorecase
+        app.set_filter("info", false, 5);
+        assert!(app.display_lines().is_empty());
+        app.mode = Mode::Command("set ic".into());
+        commands::EditorCommand::CommandSubmit.run(&mut app, &mut ctx);
+        let texts: Vec<&str> = app.display_lines().iter().map(|l| l.text()).collect();
+        assert_eq!(texts, vec!["INFO start", "INFO more"]);
+        assert_eq!(app.cursor_y, 0);
+
+        // with every line hidden there is nothing to attach a note to
+        app.set_filter("zzz", false, 5);
+        keymaps::normal::handle(&mut app, KeyEvent::from(KeyCode::Char('a')), &mut ctx);
+        assert!(matches!(app.mode, Mode::Normal));
+        assert_eq!(app.overlays.len(), 1);
+        assert_eq!(
+            app.message.as_deref(),
+            Some("Cannot add a note to a line hidden by the filter")
+        );
+    }
+
+    #[test]
+    fn pinned_highlights_layer_under_search() {
+        fn ex(app: &mut App, cmd: &str) {
+            let mut ctx = commands::Context {
+                height: 5,
+                pending_g: false,
+                pending_prefix: None,
+            };
+            app.mode = Mode::Command(cmd.to_string());
+            keymaps::command::handle(
+                app,
+                KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE),
+                &mut ctx,
+            );
+        }
+        let content = "ERROR user_id=42
+INFO user_id=7"
+            .to_string();
+        let mut app = App::new(PathBuf::new(), content, Vec::new());
+        ex(&mut app, "hl add ERROR red");
+        ex(&mut app, "hl add user_id=42 cyan");
+        ex(&mut app, "hl add \\v\\d+ green");
+        ex(&mut app, "hl add ERROR blue");
+        assert_eq!(app.highlights.len(), 3);
+        ex(&mut app, "hl");
+        assert_eq!(
+            app.message.as_deref(),
+            Some("user_id=42 (Cyan), \\v\\d+ (Green), ERROR (Blue)")
+        );
+        ex(&mut app, "hl add INFO mauve");
+        assert_eq!(app.message.as_deref(), Some("Unknown color: mauve"));
+        ex(&mut app, "hl add \\v red");
+        assert_eq!(app.message.as_deref(), Some("Usage: hl add PATTERN COLOR"));
+        assert_eq!(app.highlights.len(), 3);
+        app.set_search_query("user".into());
+
+        let backend = TestBackend::new(20, 3);
+        let mut terminal = Terminal::new(backend).unwrap();
+        terminal.draw(|f| ui(f, &app)).unwrap();
+        let bg = |x: u16, y: u16| terminal.backend().buffer()[(x, y)].bg;
+        assert_eq!(bg(0, 0), Color::Blue);
+        assert_eq!(bg(6, 0), Color::Yellow);
+        assert_eq!(bg(10, 0), Color::Cyan);
+        assert_eq!(bg(14, 0), Color::Green);
+        assert_eq!(bg(13, 1), Color::Green);
+        assert_eq!(bg(0, 1), Color::Reset);
+
+        ex(&mut app, "hl remove \\v\\d+");
+        assert_eq!(app.highlights.len(), 2);
+        ex(&mut app, "hl clear");
+        assert!(app.highlights.is_empty());
+    }
+
+    #[test]
+    fn star_and_hash_search_word_under_cursor() {
+        let content = "foo bar\nfoobar foo\nfoo_x foo\n".to_string();
+        let mut app = App::new(PathBuf::new(), content, Vec::new());
+        let mut ctx = commands::Context {
+            height: 5,
+            pending_g: false,
+            pending_prefix: None,
+        };
+        let mut press = |app: &mut App, c: char| {
+            keymaps::normal::handle(
+                app,
+                KeyEvent::new(KeyCode::Char(c), KeyModifiers::NONE),
+                &mut ctx,
+            );
+        };
+        app.cursor_x = 1;
+        press(&mut app, '*');
+        assert_eq!(app.search_hits, vec![(0, 0), (1, 7), (2, 6)]);
+        assert_eq!((app.cursor_y, app.cursor_x), (1, 7));
+        assert_eq!(app.message.as_deref(), Some("/\\<foo\\> [2/3]"));
+        assert_eq!(app.history.older("").as_deref(), Some("\\<foo\\>"));
+
+        press(&mut app, '#');
+        assert_eq!((app.cursor_y, app.cursor_x), (0, 0));
+        press(&mut app, '#');
+        assert_eq!((app.cursor_y, app.cursor_x), (2, 6));
+        assert_eq!(
+            app.message.as_deref(),
+            Some("search hit TOP, continuing at BOTTOM [3/3]")
+        );
+        press(&mut app, 'n');
+        assert_eq!((app.cursor_y, app.cursor_x), (1, 7));
+
+        // off a keyword, the next one on the line is used
+        (app.cursor_y, app.cursor_x) = (0, 3);
+        press(&mut app, '*');
+        assert_eq!(app.search_hits, vec![(0, 4)]);
+
+        app.cursor_y = 3;
+        press(&mut app, '*');
+        assert_eq!(app.message.as_deref(), Some("No string under cursor"));
+
+        app.reload("fooé foo".to_string());
+        (app.cursor_y, app.cursor_x) = (0, 0);
+        press(&mut app, '*');
+        assert_eq!(app.search_hits, vec![(0, 0), (0, 6)]);
+        assert_eq!(app.cursor_x, 6);
+    }
+
+    #[test]
+    fn line_finder_ranks_and_jumps() {
+        let content = "alpha\nbeta gamma\nbig amount\ngamma".to_string();
+        let mut app = App::new(PathBuf::new(), content, Vec::new());
+        let mut ctx = commands::Context {
+            height: 8,
+            pending_g: false,
+            pending_prefix: None,
+        };
+        fn send(app: &mut App, ctx: &mut commands::Context, code: KeyCode, mods: KeyModifiers) {
+            let key = KeyEvent::new(code, mods);
+            match app.mode {
+                Mode::Finder(_) => keymaps::finder::handle(app, key, ctx),
+                _ => keymaps::normal::handle(app, key, ctx),
+            };
+        }
+        send(
+            &mut app,
+            &mut ctx,
+            KeyCode::Char('p'),
+            KeyModifiers::CONTROL,
+        );
+        send(&mut app, &mut ctx, KeyCode::Char('g'), KeyModifiers::NONE);
+        send(&mut app, &mut ctx, KeyCode::Char('a'), KeyModifiers::NONE);
+        let Mode::Finder(finder) = &app.mode else {
+            panic!("finder not open");
+        };
+        assert_eq!(finder.results, vec![3, 1, 2]);
+
+        send(&mut app, &mut ctx, KeyCode::Down, KeyModifiers::NONE);
+        let backend = TestBackend::new(30, 10);
+        let mut terminal = Terminal::new(backend).unwrap();
+        terminal.draw(|f| ui(f, &app)).unwrap();
+        assert_snapshot!("line_finder", terminal.backend());
+
+        send(&mut app, &mut ctx, KeyCode::Enter, KeyModifiers::NONE);
+        assert!(matches!(app.mode, Mode::Normal));
+        assert_eq!((app.cursor_y, app.cursor_x), (1, 0));
+
+        app.mode = Mode::Command("lines zzz".into());
+        commands::EditorCommand::CommandSubmit.run(&mut app, &mut ctx);
+        let Mode::Finder(finder) = &app.mode else {
+            panic!("finder not open");
+        };
+        assert!(finder.results.is_empty());
+        send(&mut app, &mut ctx, KeyCode::Enter, KeyModifiers::NONE);
+        assert!(matches!(app.mode, Mode::Normal));
+        assert_eq!(app.cursor_y, 1);
+
+        app.mode = Mode::Command("lines gamma".into());
+        commands::EditorCommand::CommandSubmit.run(&mut app, &mut ctx);
+        send(
+            &mut app,
+            &mut ctx,
+            KeyCode::Char('c'),
+            KeyModifiers::CONTROL,
+        );
+        assert!(matches!(app.mode, Mode::Normal));
+        assert_eq!(app.cursor_y, 1);
+
+        // results follow a reload that shortens the file
+        app.open_finder("a");
+        app.reload("gamma".to_string());
+        let Mode::Finder(finder) = &app.mode else {
+            panic!("finder not open");
+        };
+        assert_eq!(finder.results, vec![0]);
+        terminal.draw(|f| ui(f, &app)).unwrap();
+    }
+
+    #[test]
+    fn command_templates_split_like_a_shell() {
+        let vars = |name: &str| match name {
+            "file" => Some("my notes/it's here.txt".to_string()),
+            "args" => Some(r#"one "two words" th\ ree"#.to_string()),
+            "empty" => Some(String::new()),
+            _ => None,
+        };
+        let words = |template: &str| command_spec::expand_words(template, &vars);
+        assert_eq!(
+            words("wc -l {file}").unwrap(),
+            vec!["wc", "-l", "my notes/it's here.txt"]
+        );
+        assert_eq!(
+            words("grep {args} --").unwrap(),
+            vec!["grep", "one", "two words", "th ree", "--"]
+        );
+        assert_eq!(
+            words(r#"echo "{args}" --file={file}"#).unwrap(),
+            vec![
+                "echo",
+                r#"one "two words" th\ ree"#,
+                "--file=my notes/it's here.txt"
+            ]
+        );
+        assert_eq!(
+            words(r#"awk '{print $1}' "a\"b\n" c\ d {empty} "{empty}""#).unwrap(),
+            vec!["awk", "{print $1}", "a\"b\\n", "c d", "", ""]
+        );
+        assert_eq!(words("a {unknown}").unwrap(), vec!["a", "{unknown}"]);
+        assert_eq!(
+            words("echo 'unterminated").unwrap_err(),
+            "Unterminated quote in command"
+        );
+
+        let script = |template: &str| command_spec::expand_script(template, &vars);
+        assert_eq!(
+            script("cat {file} | head").unwrap(),
+            r#"cat 'my notes/it'\''s here.txt' | head"#
+        );
+        assert_eq!(
+            script(r#"echo "{file}" '{file}'"#).unwrap(),
+            r#"echo "my notes/it's here.txt" 'my notes/it'\''s here.txt'"#
+        );
+        assert_eq!(
+            script("printf %s {args} > out").unwrap(),
+            "printf %s one 'two words' 'th ree' > out"
+        );
+        assert_eq!(
+            command_spec::shell_quote("plain-word_1.txt"),
+            "plain-word_1.txt"
+        );
+        assert_eq!(command_spec::shell_quote(""), "''");
+
+        let spec: CommandSpec = "todo$!:grep -n TODO {file} | head".parse().unwrap();
+        assert!(spec.shell && spec.capture);
+        assert_eq!(spec.name, "todo");
+        assert_eq!(spec.argv(&vars).unwrap()[..2], ["sh", "-c"]);
+    }
+
+    #[test]
+    fn command_placeholders_describe_file_cursor_and_selection() {
+        let content = "fn main() {\n    let x = 1;\n}".to_string();
+        let spec: CommandSpec = "sel!:printf %s {selection}".parse().unwrap();
+        let mut app = App::new(PathBuf::from("src/my file.rs"), content, vec![spec]);
+        app.set_search_query("let".into());
+        (app.cursor_y, app.cursor_x) = (1, 8);
+        let value = |app: &App, name: &str| app.placeholder(name, "").unwrap();
+        assert_eq!(value(&app, "file"), "src/my file.rs");
+        assert_eq!(value(&app, "dir"), "src");
+        assert_eq!(value(&app, "word"), "x");
+        assert_eq!(value(&app, "query"), "let");
+        assert_eq!(value(&app, "selection"), "");
+        assert_eq!(app.placeholder("nope", ""), None);
+
+        let mut ctx = commands::Context {
+            height: 8,
+            pending_g: false,
+            pending_prefix: None,
+        };
+        fn send(app: &mut App, ctx: &mut commands::Context, keys: &str) {
+            for c in keys.chars() {
+                let key = KeyEvent::new(KeyCode::Char(c), KeyModifiers::NONE);
+                match app.mode {
+                    Mode::Visual | Mode::VisualLine => keymaps::visual::handle(app, key, ctx),
+                    Mode::Command(_) => keymaps::command::handle(app, key, ctx),
+                    _ => keymaps::normal::handle(app, key, ctx),
+                };
+            }
+        }
+        (app.cursor_y, app.cursor_x) = (0, 3);
+        send(&mut app, &mut ctx, "vj");
+        app.cursor_x = 7;
+        assert_eq!(app.selected_text(), "main() {\n    let");
+        send(&mut app, &mut ctx, ":sel");
+        keymaps::command::handle(
+            &mut app,
+            KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE),
+            &mut ctx,
+        );
+        assert_eq!(app.selection_start, None);
+        wait_for_jobs(&mut app);
+        assert_eq!(app.overlays[0].content, vec!["main() {", "    let"]);
+
+        app.cursor_y = 0;
+        send(&mut app, &mut ctx, "Vj");
+        assert_eq!(app.selected_text(), "fn main() {\n    let x = 1;");
+        // help opens with F1 as in normal mode
+        keymaps::visual::handle(
+            &mut app,
+            KeyEvent::new(KeyCode::F(1), KeyModifiers::NONE),
+            &mut ctx,
+        );
+        assert!(matches!(app.mode, Mode::Help));
+    }
+
+    #[test]
+    fn stdin_commands_read_selection_or_file() {
+        let content = "{\"a\": 1}\n{\"b\": 2}".to_string();
+        let spec: CommandSpec = "count|!:wc -l".parse().unwrap();
+        assert!(spec.stdin && spec.capture);
+        let mut app = App::new(PathBuf::new(), content, vec![spec]);
+        let mut ctx = commands::Context {
+            height: 8,
+            pending_g: false,
+            pending_prefix: None,
+        };
+        let mut run = |app: &mut App| {
+            app.mode = Mode::Command("count".into());
+            commands::EditorCommand::CommandSubmit.run(app, &mut ctx);
+            wait_for_jobs(app);
+        };
+        run(&mut app);
+        assert_eq!(app.overlays[0].content, vec!["2"]);
+
+        app.selection_start = Some((0, 0));
+        app.selection_linewise = true;
+        run(&mut app);
+        assert_eq!(app.overlays[1].content, vec!["1"]);
+    }
+
+    #[test]
+    fn jobs_run_in_background_and_can_be_cancelled() {
+        let commands = vec![
+            "slow$:sleep 30 | cat; echo x".parse().unwrap(),
+            "fail$:echo oops >&2; exit 3".parse().unwrap(),
+        ];
+        let mut app = App::new(PathBuf::new(), "a".to_string(), commands);
+        let mut ctx = commands::Context {
+            height: 4,
+            pending_g: false,
+            pending_prefix: None,
+        };
+        app.mode = Mode::Command("slow".into());
+        commands::EditorCommand::CommandSubmit.run(&mut app, &mut ctx);
+        assert!(matches!(app.mode, Mode::Normal));
+        assert!(app.job_status().unwrap().ends_with(" slow"));
+
+        let backend = TestBackend::new(20, 3);
+        let mut terminal = Terminal::new(backend).unwrap();
+        terminal.draw(|f| ui(f, &app)).unwrap();
+        let status: String = (0..20)
+            .map(|x| terminal.backend().buffer()[(x, 2)].symbol().to_string())
+            .collect();
+        assert!(status.trim_start().ends_with("slow"), "{status:?}");
+
+        keymaps::normal::handle(
+            &mut app,
+            KeyEvent::new(KeyCode::Char('c'), KeyModifiers::CONTROL),
+            &mut ctx,
+        );
+        assert!(app.jobs.is_empty());
+        assert_eq!(app.message.as_deref(), Some("Cancelled slow"));
+        // the whole pipeline is killed, so the job reports back promptly and
+        // is ignored
+        let done = app.job_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        app.finish_job(done);
+        assert_eq!(app.message.as_deref(), Some("Cancelled slow"));
+
+        app.mode = Mode::Command("fail".into());
+        commands::EditorCommand::CommandSubmit.run(&mut app, &mut ctx);
+        wait_for_jobs(&mut app);
+        assert_eq!(app.message.as_deref(), Some("fail exited with 3: oops"));
+    }
+
+    #[test]
+    fn foreground_commands_wait_for_the_main_loop() {
+        let spec: CommandSpec = "edit@:vi +{line} {file}".parse().unwrap();
+        assert!(spec.foreground);
+        assert!("edit!@:vi".parse::<CommandSpec>().is_err());
+        let mut app = App::new(PathBuf::from("notes.txt"), "a\nb".to_string(), vec![spec]);
+        app.cursor_y = 1;
+        let mut ctx = commands::Context {
+            height: 4,
+            pending_g: false,
+            pending_prefix: None,
+        };
+        app.mode = Mode::Command("edit".into());
+        commands::EditorCommand::CommandSubmit.run(&mut app, &mut ctx);
+        assert!(app.jobs.is_empty());
+        let command = app.foreground.take().unwrap();
+        assert_eq!(command.argv, vec!["vi", "+2", "notes.txt"]);
+        assert_eq!(command.input, None);
+    }
 }
@@ -0,0 +1,64 @@
+/// Settings changed at runtime with `:set`.
+#[derive(Clone, Default)]
+pub struct Options {
+    /// Word and paragraph motions stop on overlay lines.
+    pub overlay_motion: bool,
+    /// Search matches text inside overlays.
+    pub overlay_search: bool,
+    /// Search ignores case.
+    pub ignore_case: bool,
+    /// With `ignore_case`, queries containing uppercase letters match case.
+    pub smart_case: bool,
+}
+
+impl Options {
+    fn flag(&mut self, name: &str) -> Option<&mut bool> {
+        match name {
+            "overlaymotion" => Some(&mut self.overlay_motion),
+            "overlaysearch" => Some(&mut self.overlay_search),
+            "ignorecase" | "ic" => Some(&mut self.ignore_case),
+            "smartcase" | "scs" => Some(&mut self.smart_case),
+            _ => None,
+        }
+    }
+
+    /// Applies `:set` arguments such as `overlaysearch`, `nooverlaysearch` or
+    /// `overlaysearch!` (toggle). Nothing changes unless every argument is
+    /// valid.
+    pub fn apply(&mut self, args: &str) -> Result<(), String> {
+        let mut updated = self.clone();
+        for arg in args.split_whitespace() {
+            if let Some(name) = arg.strip_suffix('!')
+                && let Some(flag) = updated.flag(name)
+            {
+                *flag = !*flag;
+            } else if let Some(flag) = updated.flag(arg) {
+                *flag = true;
+            } else if let Some(flag) = arg.strip_prefix("no").and_then(|n| updated.flag(n)) {
+                *flag = false;
+            } else {
+                return Err(format!("Unknown option: {arg}"));
+            }
+        }
+        *self = updated;
+        Ok(())
+    }
+
+    /// Current settings in `:set` syntax.
+    pub fn summary(&self) -> String {
+        let show = |name: &str, on: bool| {
+            if on {
+                name.to_string()
+            } else {
+                format!("no{name}")
+            }
+        };
+        [
+            show("overlaymotion", self.overlay_motion),
+            show("overlaysearch", self.overlay_search),
+            show("ignorecase", self.ignore_case),
+            show("smartcase", self.smart_case),
+        ]
+        .join(" ")
+    }
+}
@@ -0,0 +1,157 @@
+use ratatui::style::{Color, Modifier, Style};
+use serde::{Deserialize, Serialize};
+
+use crate::OverlayItem;
+
+/// Width of the gutter marker and left border drawn before overlay text.
+pub const GUTTER_WIDTH: usize = 3;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverlayKind {
+    #[default]
+    Info,
+    Warning,
+    Error,
+    Note,
+    DiffAdded,
+}
+
+impl OverlayKind {
+    /// The name used in overlay records and sidecars, e.g. `diff-added`.
+    pub fn name(self) -> &'static str {
+        match self {
+            OverlayKind::Info => "info",
+            OverlayKind::Warning => "warning",
+            OverlayKind::Error => "error",
+            OverlayKind::Note => "note",
+            OverlayKind::DiffAdded => "diff-added",
+        }
+    }
+
+    pub fn marker(self) -> char {
+        match self {
+            OverlayKind::Info => 'i',
+            OverlayKind::Warning => '!',
+            OverlayKind::Error => 'x',
+            OverlayKind::Note => '*',
+            OverlayKind::DiffAdded => '+',
+        }
+    }
+
+    pub fn style(self) -> Style {
+        let color = match self {
+            OverlayKind::Info => Color::Cyan,
+            OverlayKind::Warning => Color::Yellow,
+            OverlayKind::Error => Color::Red,
+            OverlayKind::Note => Color::Magenta,
+            OverlayKind::DiffAdded => Color::Green,
+        };
+        Style::default().fg(color)
+    }
+
+    /// Style for the gutter marker and border.
+    pub fn gutter_style(self) -> Style {
+        self.style().add_modifier(Modifier::BOLD)
+    }
+}
+
+/// A single overlay record emitted by an external tool, one JSON object per
+/// line of stdout: `{"after_line": 12, "content": ["..."], "kind": "warning"}`.
+#[derive(Deserialize)]
+struct OverlayRecord {
+    /// 1-based line number the overlay is shown after.
+    after_line: usize,
+    content: Vec<String>,
+    #[serde(default)]
+    kind: OverlayKind,
+}
+
+/// Parses captured command output as overlay records. Returns `None` unless
+/// every non-blank line is a valid record, so plain text output can fall back
+/// to a single overlay.
+pub fn parse_records(output: &str) -> Option<Vec<OverlayItem>> {
+    let mut items = Vec::new();
+    for line in output.lines().filter(|l| !l.trim().is_empty()) {
+        let record: OverlayRecord = serde_json::from_str(line).ok()?;
+        items.push(OverlayItem {
+            id: 0,
+            after_line: record.after_line.saturating_sub(1),
+            content: record.content,
+            kind: record.kind,
+            collapsed: false,
+        });
+    }
+    if items.is_empty() { None } else { Some(items) }
+}
+
+/// Upper bound on the LCS table size; larger changed regions are treated as
+/// a single hunk.
+const MAX_DIFF_CELLS: usize = 4_000_000;
+
+/// Maps each line of `old` to its index in `new`, or `None` if the line was
+/// deleted. Uses a longest-common-subsequence diff over the region between
+/// the common prefix and suffix; within each changed hunk, old lines are
+/// paired in order with their replacements and only the surplus is deleted.
+pub fn line_mapping(old: &[String], new: &[String]) -> Vec<Option<usize>> {
+    let mut mapping = vec![None; old.len()];
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    for (i, m) in mapping.iter_mut().enumerate().take(prefix) {
+        *m = Some(i);
+    }
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    for i in 0..suffix {
+        mapping[old.len() - 1 - i] = Some(new.len() - 1 - i);
+    }
+
+    let old_mid = &old[prefix..old.len() - suffix];
+    let new_mid = &new[prefix..new.len() - suffix];
+    let (n, m) = (old_mid.len(), new_mid.len());
+    // matching lines in `old_mid` and `new_mid`, ending with the region's end
+    let mut matches = Vec::new();
+    if n.saturating_mul(m) <= MAX_DIFF_CELLS {
+        // lcs[i][j] is the LCS length of old_mid[i..] and new_mid[j..]
+        let width = m + 1;
+        let mut lcs = vec![0u32; (n + 1) * width];
+        for i in (0..n).rev() {
+            for j in (0..m).rev() {
+                lcs[i * width + j] = if old_mid[i] == new_mid[j] {
+                    lcs[(i + 1) * width + j + 1] + 1
+                } else {
+                    lcs[(i + 1) * width + j].max(lcs[i * width + j + 1])
+                };
+            }
+        }
+        let (mut i, mut j) = (0, 0);
+        while i < n && j < m {
+            if old_mid[i] == new_mid[j] {
+                matches.push((i, j));
+                i += 1;
+                j += 1;
+            } else if lcs[(i + 1) * width + j] >= lcs[i * width + j + 1] {
+                i += 1;
+            } else {
+                j += 1;
+            }
+        }
+    }
+    matches.push((n, m));
+
+    let (mut i, mut j) = (0, 0);
+    for (next_i, next_j) in matches {
+        // the hunk before this match: old_mid[i..next_i] became new_mid[j..next_j]
+        for k in 0..(next_i - i).min(next_j - j) {
+            mapping[prefix + i + k] = Some(prefix + j + k);
+        }
+        if next_i < n {
+            mapping[prefix + next_i] = Some(prefix + next_j);
+        }
+        (i, j) = (next_i + 1, next_j + 1);
+    }
+    mapping
+}
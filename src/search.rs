@@ -0,0 +1,170 @@
+use ratatui::style::Color;
+use regex::{Regex, RegexBuilder};
+
+use crate::options::Options;
+
+/// Prefix that makes the rest of a search query a regular expression.
+pub const REGEX_PREFIX: &str = "\\v";
+
+/// Mark the start and end of a keyword in a plain query, as in Vim's
+/// `\<foo\>`.
+const WORD_START: &str = "\\<";
+const WORD_END: &str = "\\>";
+
+/// A compiled search query. Hits and highlights both come from `matches` so
+/// they always agree.
+pub struct Pattern {
+    /// The query as typed, kept so it can be recompiled when options change.
+    pub query: String,
+    matcher: Matcher,
+}
+
+enum Matcher {
+    Literal(String),
+    Regex(Regex),
+}
+
+/// Removes `\c` and `\C` from `query`, returning the remaining text and the
+/// case sensitivity they ask for (`\c` ignores case, `\C` matches it).
+fn strip_case_flags(query: &str) -> (String, Option<bool>) {
+    let mut text = String::with_capacity(query.len());
+    let mut ignore_case = None;
+    let mut chars = query.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            text.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('c') => ignore_case = Some(true),
+            Some('C') => ignore_case = Some(false),
+            Some(next) => {
+                text.push(c);
+                text.push(next);
+            }
+            None => text.push(c),
+        }
+    }
+    (text, ignore_case)
+}
+
+/// Whether `query` is empty once `\c`, `\C`, the `\v` prefix and keyword
+/// boundaries are removed. Such a query would match everywhere, so it counts
+/// as no query at all.
+pub fn is_empty_query(query: &str) -> bool {
+    let (text, _) = strip_case_flags(query);
+    match text.strip_prefix(REGEX_PREFIX) {
+        Some(re) => re.is_empty(),
+        None => text
+            .replace(WORD_START, "")
+            .replace(WORD_END, "")
+            .is_empty(),
+    }
+}
+
+/// Regex source for a plain query using `WORD_START` or `WORD_END`. The
+/// boundaries are ASCII-only so they agree with what `*` takes as a keyword.
+fn word_bounded(text: &str) -> Option<String> {
+    if !text.contains(WORD_START) && !text.contains(WORD_END) {
+        return None;
+    }
+    let escaped = regex::escape(text);
+    let bound = "(?-u:\\b)";
+    Some(
+        escaped
+            .replace(&regex::escape(WORD_START), bound)
+            .replace(&regex::escape(WORD_END), bound),
+    )
+}
+
+impl Pattern {
+    /// Compiles `query`; queries starting with `\v` are regular expressions,
+    /// and plain queries may use `\<` and `\>` for keyword boundaries.
+    /// Case sensitivity follows `ignorecase` and `smartcase` unless the query
+    /// contains `\c` or `\C`. A pattern with no text left matches nothing.
+    pub fn new(query: &str, options: &Options) -> Result<Self, String> {
+        let (text, flag) = strip_case_flags(query);
+        let (regex, text) = match text.strip_prefix(REGEX_PREFIX) {
+            Some(re) => (true, re.to_string()),
+            None => match word_bounded(&text) {
+                Some(re) => (true, re),
+                None => (false, text),
+            },
+        };
+        let ignore_case = flag.unwrap_or_else(|| {
+            options.ignore_case && !(options.smart_case && text.chars().any(char::is_uppercase))
+        });
+        let matcher = if (!regex && !ignore_case) || text.is_empty() {
+            Matcher::Literal(text)
+        } else {
+            let source = if regex { text } else { regex::escape(&text) };
+            let re = RegexBuilder::new(&source)
+                .case_insensitive(ignore_case)
+                .build()
+                .map_err(|err| {
+                    // regex errors span several lines; the last one names the problem
+                    let err = err.to_string();
+                    let reason = err.lines().last().unwrap_or_default();
+                    format!("Invalid pattern: {}", reason.trim_start_matches("error: "))
+                })?;
+            Matcher::Regex(re)
+        };
+        Ok(Self {
+            query: query.to_string(),
+            matcher,
+        })
+    }
+
+    /// Byte ranges of the matches in `line`.
+    pub fn matches(&self, line: &str) -> Vec<(usize, usize)> {
+        match &self.matcher {
+            Matcher::Literal(q) if q.is_empty() => Vec::new(),
+            Matcher::Literal(q) => line
+                .match_indices(q.as_str())
+                .map(|(start, m)| (start, start + m.len()))
+                .collect(),
+            Matcher::Regex(re) => re.find_iter(line).map(|m| (m.start(), m.end())).collect(),
+        }
+    }
+}
+
+/// Keeps only the document lines matching `pattern`, or with `inverse` only
+/// those that don't.
+pub struct Filter {
+    pub pattern: Pattern,
+    pub inverse: bool,
+}
+
+impl Filter {
+    pub fn keeps(&self, line: &str) -> bool {
+        self.pattern.matches(line).is_empty() == self.inverse
+    }
+}
+
+/// A pattern pinned with `:hl add`, highlighted in its own color
+/// independently of the current search.
+pub struct Highlight {
+    pub pattern: Pattern,
+    pub color: Color,
+}
+
+impl Highlight {
+    /// Parses `PATTERN COLOR`; the color is the last word so the pattern
+    /// may contain spaces.
+    pub fn parse(spec: &str, options: &Options) -> Result<Self, String> {
+        let Some((query, color)) = spec
+            .trim()
+            .rsplit_once(' ')
+            .filter(|(query, _)| !is_empty_query(query.trim_end()))
+        else {
+            return Err("Usage: hl add PATTERN COLOR".to_string());
+        };
+        let color = color
+            .parse::<Color>()
+            .map_err(|_| format!("Unknown color: {color}"))?;
+        Ok(Self {
+            pattern: Pattern::new(query.trim_end(), options)?,
+            color,
+        })
+    }
+}
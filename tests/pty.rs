@@ -84,3 +84,50 @@ fn test_custom_command_execution() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_foreground_command_gets_terminal() -> anyhow::Result<()> {
+    let mut file = NamedTempFile::new()?;
+    writeln!(file, "hello world")?;
+
+    let out = NamedTempFile::new()?;
+
+    let mut script = NamedTempFile::new()?;
+    writeln!(script, "#!/bin/sh")?;
+    writeln!(script, "printf 'name? '")?;
+    writeln!(script, "read answer")?;
+    writeln!(script, "echo \"$answer\" >> {}", out.path().display())?;
+    script.flush()?;
+    let script_path = script.into_temp_path();
+    let mut perms = std::fs::metadata(&script_path)?.permissions();
+    perms.set_mode(0o755);
+    std::fs::set_permissions(&script_path, perms)?;
+
+    let cmd_spec = format!("ask@:{}", script_path.display());
+
+    let mut p = spawn(
+        &format!(
+            "target/debug/file-viewer {} --command \"{}\"",
+            file.path().display(),
+            cmd_spec
+        ),
+        Some(5_000),
+    )?;
+
+    std::thread::sleep(std::time::Duration::from_millis(200));
+    p.send(":ask\r")?;
+    p.flush()?;
+    p.exp_string("name? ")?;
+    // the line is only delivered on Enter if raw mode was turned off
+    p.send("typed answer\r")?;
+    p.flush()?;
+    std::thread::sleep(std::time::Duration::from_millis(500));
+    p.send("q")?;
+    p.flush()?;
+    p.exp_eof()?;
+
+    let output = std::fs::read_to_string(out.path())?;
+    assert_eq!(output.trim(), "typed answer");
+
+    Ok(())
+}